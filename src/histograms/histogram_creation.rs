@@ -1,20 +1,479 @@
 use polars::prelude::*;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 use std::path::PathBuf;
 
 use std::f64::consts::PI;
 
 
-use crate::utils::histogrammer::Histogrammer;
+use crate::utils::cut::CutHandler;
+use crate::utils::histogrammer::{check_cancel, FillProgress, Histogrammer, HistogramTypes, RecalibrationCache, Subsystem, nice_range};
+use crate::utils::settings::{active_time_calibration, BothPlanesDefinition, DelayAverageMode, Settings, TimeCalibration, TIME_COLUMN_NAMES};
 
-pub fn add_histograms(file_paths: Arc<[PathBuf]>) -> Result<Histogrammer, PolarsError> {
+// Name of the event number column used when no timestamp column is set
+const EVENT_NUMBER_COLUMN: &str = "EventNumber";
+
+// Scans the files. The schemas are compared first and columns that are only in some of the files are
+// reported. Those columns are either dropped or filled with nulls for the files that don't have them.
+fn scan_files(file_paths: Arc<[PathBuf]>, settings: &Settings, report: &mut Vec<String>) -> Result<LazyFrame, PolarsError> {
+    let args = settings.scan_args();
+
+    let mut schemas = Vec::new();
+    for path in file_paths.iter() {
+        schemas.push(LazyFrame::scan_parquet(path, args.clone())?.schema()?);
+    }
+
+    let Some(first_schema) = schemas.first() else {
+        return LazyFrame::scan_parquet_files(file_paths, args);
+    };
+
+    // Columns in every file, in the order of the first file
+    let common_columns: Vec<String> = first_schema.iter_names()
+        .filter(|name| schemas.iter().all(|schema| schema.contains(name)))
+        .map(|name| name.to_string())
+        .collect();
+
+    let mut partial_columns: Vec<String> = Vec::new();
+    for schema in schemas.iter() {
+        for name in schema.iter_names() {
+            if !common_columns.iter().any(|column| column == name.as_str()) && !partial_columns.iter().any(|column| column == name.as_str()) {
+                partial_columns.push(name.to_string());
+            }
+        }
+    }
+
+    if partial_columns.is_empty() {
+        return LazyFrame::scan_parquet_files(file_paths, args);
+    }
+
+    for column in partial_columns.iter() {
+        let missing_from: Vec<String> = file_paths.iter().zip(schemas.iter())
+            .filter(|(_, schema)| !schema.contains(column))
+            .filter_map(|(path, _)| path.file_stem().and_then(|stem| stem.to_str()).map(str::to_string))
+            .collect();
+        report.push(format!("Column '{}' is missing from {} of {} files: {}", column, missing_from.len(), file_paths.len(), missing_from.join(", ")));
+    }
+
+    // Reading files with different columns in one scan isn't supported, so each file is scanned on its own
+    let lfs = file_paths.iter()
+        .map(|path| LazyFrame::scan_parquet(path, args.clone()))
+        .collect::<Result<Vec<LazyFrame>, PolarsError>>()?;
+
+    if settings.common_columns_only {
+        report.push(format!("Using the {} columns found in every file", common_columns.len()));
+
+        let common_exprs: Vec<Expr> = common_columns.iter().map(|column| col(column)).collect();
+        let lfs: Vec<LazyFrame> = lfs.into_iter().map(|lf| lf.select(common_exprs.clone())).collect();
+        concat(lfs, UnionArgs::default())
+    } else {
+        report.push("Columns missing from a file are empty (null) for its events".to_string());
+
+        concat_lf_diagonal(lfs, UnionArgs::default())
+    }
+}
+
+// Adds the expressions whose input columns are all in the LazyFrame, so files without some of the
+// detector columns can still be used for the other histograms.
+fn with_available_columns(lf: LazyFrame, expressions: Vec<Expr>) -> Result<LazyFrame, PolarsError> {
+    let schema = lf.schema()?;
+    let available: Vec<Expr> = expressions.into_iter()
+        .filter(|expr| expr.clone().meta().root_names().iter().all(|name| schema.contains(name)))
+        .collect();
+
+    if available.is_empty() {
+        Ok(lf)
+    } else {
+        Ok(lf.with_columns(available))
+    }
+}
+
+// Average of the left and right delay line energies. Events where a side didn't fire (sentinel) either get
+// the energy of the other side or are left out of the average (set to the sentinel).
+fn delay_average(left: &str, right: &str, mode: DelayAverageMode, sentinel: f64) -> Expr {
+    let left_valid = col(left).neq(lit(sentinel));
+    let right_valid = col(right).neq(lit(sentinel));
+    let average = (col(left) + col(right)) / lit(2.0);
+
+    match mode {
+        DelayAverageMode::Exclude => when(left_valid.and(right_valid))
+            .then(average)
+            .otherwise(lit(sentinel)),
+        DelayAverageMode::UseValidSide => when(left_valid.clone().and(right_valid.clone()))
+            .then(average)
+            .when(left_valid)
+            .then(col(left))
+            .when(right_valid)
+            .then(col(right))
+            .otherwise(lit(sentinel)),
+    }
+}
+
+// Energy vs a monotonically increasing column (a timestamp or the event number) for monitoring gain drift over a run.
+fn add_run_progression(h: &mut Histogrammer, lf: &LazyFrame, settings: &Settings, cancel: &AtomicBool) -> Result<(), PolarsError> {
+    let schema = lf.schema()?;
+    for column in [settings.progression_column.as_str(), settings.progression_energy_column.as_str()] {
+        if !column.is_empty() && !schema.contains(column) {
+            h.load_report.push(format!("Skipped the run progression: column '{}' is not in the files", column));
+            return Ok(());
+        }
+    }
+
+    let (lf, progression_column) = if settings.progression_column.is_empty() {
+        (lf.clone().with_row_index(EVENT_NUMBER_COLUMN, None), EVENT_NUMBER_COLUMN)
+    } else {
+        (lf.clone(), settings.progression_column.as_str())
+    };
+
+    // The range of the progression column comes from the data
+    let sentinel = settings.sentinel;
+    let range_df = lf.clone()
+        .filter(col(progression_column).neq(lit(sentinel)))
+        .select([
+            col(progression_column).cast(DataType::Float64).min().alias("min"),
+            col(progression_column).cast(DataType::Float64).max().alias("max"),
+        ])
+        .collect()?;
+
+    let (Some(min), Some(max)) = (range_df.column("min")?.f64()?.get(0), range_df.column("max")?.f64()?.get(0)) else {
+        return Ok(()); // no events
+    };
+    check_cancel(cancel)?;
+
+    let energy_column = settings.progression_energy_column.as_str();
+    let name = format!("{} v {}", energy_column, progression_column);
+
+    // + 1.0 so the last event is inside the range
+    let (range, bins) = if settings.snap_ranges {
+        nice_range((min, max + 1.0), settings.progression_bins)
+    } else {
+        ((min, max + 1.0), settings.progression_bins)
+    };
+
+    // The energy column can be anything, so its axis is framed around the data
+    h.add_fill_hist2d_auto(&name, &lf, progression_column, bins, Some(range), energy_column, 512, None, settings.snap_ranges);
+
+    Ok(())
+}
+
+// Builds the histograms from the files. If cuts are given, only the events inside the cuts are used.
+// The histograms in per_file are also built from each file on its own, only for this load.
+// progress is called as the events are filled. Setting cancel stops the build with an error.
+pub fn add_histograms(file_paths: Arc<[PathBuf]>, settings: &Settings, cuts: Option<&CutHandler>, per_file: &[String], progress: &dyn Fn(FillProgress), cancel: &AtomicBool) -> Result<Histogrammer, PolarsError> {
+    let mut h = build_histograms(file_paths.clone(), settings, cuts.as_slice(), progress, cancel, None)?;
+
+    if !per_file.is_empty() {
+        add_per_file_histograms(&mut h, &file_paths, settings, cuts, per_file, progress, cancel)?;
+    }
+
+    Ok(h)
+}
+
+// Builds only the histograms in names, the same way as add_histograms (calibrations, selections, sampling),
+// with the events inside the cut. With the cuts of the loaded histograms, the events must be inside those too.
+// See Histogrammer::replace_refilled to put them in place of the loaded ones.
+pub fn refill_histograms(file_paths: Arc<[PathBuf]>, settings: &Settings, loaded_cuts: Option<&CutHandler>, cut: &CutHandler, names: &[String], progress: &dyn Fn(FillProgress), cancel: &AtomicBool) -> Result<Histogrammer, PolarsError> {
+    let cuts: Vec<&CutHandler> = loaded_cuts.into_iter().chain([cut]).collect();
+    build_histograms(file_paths, settings, &cuts, progress, cancel, Some(names))
+}
+
+// Builds the per file histograms from each file on its own, named "{name} [{file stem}]", to compare runs.
+// Files with the same stem (e.g. from different directories) get " (2)", " (3)", ... appended.
+fn add_per_file_histograms(h: &mut Histogrammer, file_paths: &[PathBuf], settings: &Settings, cuts: Option<&CutHandler>, per_file: &[String], progress: &dyn Fn(FillProgress), cancel: &AtomicBool) -> Result<(), PolarsError> {
+    for path in file_paths.iter() {
+        check_cancel(cancel)?;
+        let file_stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("file").to_string();
+        let stem = std::iter::once(file_stem.clone())
+            .chain((2..).map(|copy| format!("{} ({})", file_stem, copy)))
+            .find(|stem| !h.per_file_stems.contains(stem))
+            .unwrap_or(file_stem);
+
+        // The progress of each file, with the counts under the names of the copies
+        let file_progress = |file_progress: FillProgress| progress(FillProgress {
+            histogram_counts: file_progress.histogram_counts.into_iter()
+                .map(|(name, counts)| (format!("{} [{}]", name, stem), counts))
+                .collect(),
+            ..file_progress
+        });
+
+        let single_file = build_histograms(Arc::from(vec![path.clone()]), settings, cuts.as_slice(), &file_progress, cancel, Some(per_file))?;
+
+        for (name, hist) in single_file.histogram_list {
+            let copy_name = format!("{} [{}]", name, stem);
+            h.copy_subsystem(&name, &copy_name);
+            h.histogram_list.insert(copy_name, hist);
+        }
+        h.load_report.extend(single_file.load_report.into_iter().map(|line| format!("{}: {}", stem, line)));
+        h.per_file_stems.push(stem);
+    }
+
+    Ok(())
+}
+
+// Name of the column with the number of CeBrA detectors that fired in each event
+const CEBRA_MULTIPLICITY_COLUMN: &str = "CebraMultiplicity";
+
+// Selection of the events with at least settings.cebra_min_multiplicity detectors
+const CEBRA_SELECTION_COLUMN: &str = "cebra_min_multiplicity";
+
+// Cebra{n}Energy columns of the files
+fn cebra_energy_columns(lf: &LazyFrame) -> Result<Vec<String>, PolarsError> {
+    let schema = lf.schema()?;
+    Ok(schema.iter_names()
+        .filter(|name| {
+            name.strip_prefix("Cebra")
+                .and_then(|rest| rest.strip_suffix("Energy"))
+                .is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+        })
+        .map(|name| name.to_string())
+        .collect())
+}
+
+// Adds the CeBrA multiplicity column and its histogram (of all events). With a minimum multiplicity, also
+// adds the selection column of the events with at least that many detectors and returns its name, to select
+// the CeBrA histograms with. The other histograms keep every event.
+fn add_cebra_multiplicity(h: &mut Histogrammer, lf: LazyFrame, settings: &Settings) -> Result<(LazyFrame, Option<&'static str>), PolarsError> {
+    let columns = cebra_energy_columns(&lf)?;
+
+    // A detector fired if its energy isn't the no value marker (or missing from the file)
+    let Some(multiplicity) = columns.iter()
+        .map(|column| col(column).neq(lit(settings.sentinel)).fill_null(lit(false)).cast(DataType::UInt32))
+        .reduce(|total, fired| total + fired)
+    else {
+        if settings.cebra_min_multiplicity > 0 {
+            h.load_report.push("No Cebra{n}Energy columns in the files, the CeBrA multiplicity selection is not applied".to_string());
+        }
+        return Ok((lf, None));
+    };
+
+    let mut lf = lf.with_column(multiplicity.alias(CEBRA_MULTIPLICITY_COLUMN));
+    let selection = (settings.cebra_min_multiplicity > 0).then(|| {
+        lf = lf.clone().with_column(col(CEBRA_MULTIPLICITY_COLUMN).gt_eq(lit(settings.cebra_min_multiplicity as u32)).alias(CEBRA_SELECTION_COLUMN));
+        CEBRA_SELECTION_COLUMN
+    });
+
+    let bins = columns.len() + 1;
+    h.with_subsystem(Subsystem::Cebra, |h| {
+        h.add_batch_hist1d("CeBrA Multiplicity", CEBRA_MULTIPLICITY_COLUMN, bins, (0.0, bins as f64), None);
+    });
+
+    Ok((lf, selection))
+}
+
+// Time of each CeBrA detector relative to the left scintillator against the focal plane position, one 2D
+// histogram per Cebra{n}Time column, to check the timing across the focal plane. Events where either
+// time is missing get the no value marker. The histograms are filled with the events of `selection`.
+fn add_cebra_time_to_scint(h: &mut Histogrammer, lf: LazyFrame, settings: &Settings, selection: Option<&str>) -> Result<LazyFrame, PolarsError> {
+    let schema = lf.schema()?;
+    if !schema.contains("ScintLeftTime") {
+        return Ok(lf);
+    }
+
+    let energy_columns = cebra_energy_columns(&lf)?;
+    let mut expressions = Vec::new();
+    h.with_subsystem(Subsystem::Cebra, |h| {
+        for energy_column in energy_columns {
+            let detector = energy_column.trim_end_matches("Energy");
+            let time_column = format!("{}Time", detector);
+            if !schema.contains(&time_column) {
+                continue;
+            }
+
+            let column = format!("{}_ScintLeftTime", time_column);
+            expressions.push(
+                when(col(&time_column).neq(lit(settings.sentinel)).and(col("ScintLeftTime").neq(lit(settings.sentinel))))
+                    .then(col(&time_column) - col("ScintLeftTime"))
+                    .otherwise(lit(settings.sentinel))
+                    .alias(&column)
+            );
+            h.add_batch_hist2d(&format!("{}TimeToScint v Xavg", detector), "Xavg", 600, (-300.0, 300.0), &column, 1600, settings.time_range(&column, (-3200.0, 3200.0)), selection);
+        }
+    });
+
+    Ok(if expressions.is_empty() { lf } else { lf.with_columns(expressions) })
+}
+
+// Xavg of the files minus the Xavg computed from X1 and X2
+const XAVG_CHECK_COLUMN: &str = "XavgProvided_XavgComputed";
+
+// Position of every event where a plane fired, see build_histograms
+const ANY_PLANE_POSITION_COLUMN: &str = "XavgAnyPlane";
+
+// Column with the event number, only used while sampling
+const SAMPLE_ROW_COLUMN: &str = "SampleRow";
+
+// Keeps about `fraction` of the events. Each event number is scrambled by multiplicative hashing into [0, 1)
+// and compared with the fraction, so the kept events are spread over the whole run and are the same on
+// every load.
+fn sample_events(lf: LazyFrame, fraction: f64) -> LazyFrame {
+    const HASH_MULTIPLIER: u64 = 2_654_435_761; // 2^32 / golden ratio
+    const HASH_RANGE: u64 = 1 << 32;
+
+    let scrambled = (col(SAMPLE_ROW_COLUMN).cast(DataType::UInt64) * lit(HASH_MULTIPLIER)) % lit(HASH_RANGE);
+    lf.with_row_index(SAMPLE_ROW_COLUMN, None)
+        .filter(scrambled.lt(lit((fraction * HASH_RANGE as f64) as u64)))
+        .drop([SAMPLE_ROW_COLUMN])
+}
+
+// Slope and offset of a column, (1, 0) if it isn't calibrated. Same calibration as Settings::time_range.
+fn linear_calibration(calibrations: &[TimeCalibration], column: &str) -> (f64, f64) {
+    active_time_calibration(calibrations, column)
+        .map_or((1.0, 0.0), |calibration| (calibration.slope, calibration.offset))
+}
+
+// Collects every column the histograms of calibratable time columns are filled from, None if there are none.
+fn recalibration_cache(h: &Histogrammer, lf: &LazyFrame, settings: &Settings) -> Result<Option<Box<RecalibrationCache>>, PolarsError> {
+    let schema = lf.schema()?;
+    let mut columns: Vec<&str> = Vec::new();
+    let mut ranges = HashMap::new();
+    for (name, histogram) in &h.histogram_list {
+        let Some((values, selection)) = h.source_columns(name) else {
+            continue;
+        };
+        if !values.iter().any(|column| TIME_COLUMN_NAMES.contains(column)) {
+            continue;
+        }
+
+        // Undo the calibration of the range, a calibration with a slope of 0 is never applied
+        let axis_ranges = match histogram {
+            HistogramTypes::Hist1D(hist) => vec![hist.range],
+            HistogramTypes::Hist2D(hist) => vec![hist.x_range, hist.y_range],
+        };
+        let raw_ranges = values.iter().zip(axis_ranges)
+            .map(|(column, range)| {
+                let (slope, offset) = linear_calibration(&settings.time_calibrations, column);
+                let [start, end] = [range.0, range.1].map(|value| (value - offset) / slope);
+                (start.min(end), start.max(end))
+            })
+            .collect();
+        ranges.insert(name.clone(), raw_ranges);
+
+        for column in values.into_iter().chain(selection) {
+            if schema.contains(column) && !columns.contains(&column) {
+                columns.push(column);
+            }
+        }
+    }
+
+    if columns.is_empty() {
+        return Ok(None);
+    }
+
+    let frame = lf.clone().select(columns.iter().map(|&column| col(column)).collect::<Vec<Expr>>()).collect()?;
+    Ok(Some(Box::new(RecalibrationCache { frame, ranges, calibrations: settings.time_calibrations.clone() })))
+}
+
+// Brings the histograms of a build with live recalibration up to date with the time calibrations in settings.
+// Only the histograms of a column whose calibration changed are refilled, their range follows the calibration
+// the same way as when building. Returns the names of the refilled histograms, none without a cache. A histogram
+// that can't be refilled keeps its previous contents and the error is returned.
+pub fn recalibrate(h: &mut Histogrammer, settings: &Settings) -> Result<Vec<String>, PolarsError> {
+    let Some(mut cache) = h.recalibration.take() else {
+        return Ok(Vec::new());
+    };
+    let result = refill_recalibrated(h, &cache, settings);
+    cache.calibrations = settings.time_calibrations.clone();
+    h.recalibration = Some(cache);
+    result
+}
+
+fn refill_recalibrated(h: &mut Histogrammer, cache: &RecalibrationCache, settings: &Settings) -> Result<Vec<String>, PolarsError> {
+
+    let changed: Vec<&str> = TIME_COLUMN_NAMES.iter().copied()
+        .filter(|column| linear_calibration(&cache.calibrations, column) != linear_calibration(&settings.time_calibrations, column))
+        .collect();
+    if changed.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let lf = with_available_columns(cache.frame.clone().lazy(), settings.time_calibration_expressions())?;
+
+    let mut names: Vec<&String> = cache.ranges.keys()
+        .filter(|name| h.source_columns(name).is_some_and(|(columns, _)| columns.iter().any(|column| changed.contains(column))))
+        .collect();
+    names.sort();
+
+    let mut result = Ok(());
+    for &name in &names {
+        let (columns, _) = h.source_columns(name).unwrap_or_default();
+        let ranges: Vec<(f64, f64)> = columns.iter().zip(&cache.ranges[name])
+            .map(|(column, &range)| settings.time_range(column, range))
+            .collect();
+        if let Err(e) = h.refill_with_ranges(name, &ranges, &lf) {
+            result = Err(e);
+        }
+    }
+
+    // The cache has the events of all files together, so the per file copies can't be refilled from it
+    let stale: Vec<String> = names.iter()
+        .flat_map(|name| h.per_file_stems.iter().map(move |stem| format!("{} [{}]", name, stem)))
+        .filter(|copy_name| h.histogram_list.contains_key(copy_name))
+        .map(|copy_name| format!("'{}' still has the previous time calibration, build it per file again to update it", copy_name))
+        .filter(|message| !h.load_report.contains(message))
+        .collect();
+    h.load_report.extend(stale);
+
+    result.map(|_| names.into_iter().cloned().collect())
+}
+
+// Builds the histograms, or only the ones in `only` if given.
+fn build_histograms(file_paths: Arc<[PathBuf]>, settings: &Settings, cuts: &[&CutHandler], progress: &dyn Fn(FillProgress), cancel: &AtomicBool, only: Option<&[String]>) -> Result<Histogrammer, PolarsError> {
     
-    let args = ScanArgsParquet::default();
+    let mut report = Vec::new();
 
     // Load multiple parquet files
-    let lf = LazyFrame::scan_parquet_files(file_paths, args)?;
+    let lf = scan_files(file_paths, settings, &mut report)?;
+
+    // A quick look at part of the data
+    let lf = if settings.sample_fraction < 1.0 {
+        report.push(format!("Sampled build: only {:.1}% of the events are used", settings.sample_fraction * 100.0));
+        sample_events(lf, settings.sample_fraction)
+    } else {
+        lf
+    };
 
     let mut h = Histogrammer::new();
+    h.storage_2d = settings.hist2d_storage;
+    h.load_report = report;
+    h.sentinel = settings.sentinel;
+    h.fill_invalid = settings.fill_invalid;
+    h.sample_fraction = settings.sample_fraction.min(1.0);
+    let sentinel = settings.sentinel;
+
+    let (lf, cebra_selection) = add_cebra_multiplicity(&mut h, lf, settings)?;
+
+    // Older files don't have Xavg, it is the weighted average of the two planes
+    let schema = lf.schema()?;
+    let has_planes = schema.contains("X1") && schema.contains("X2");
+    let weighted_xavg = col("X1") * lit(settings.xavg_x1_weight) + col("X2") * lit(1.0 - settings.xavg_x1_weight);
+
+    // Xavg of the files against the same formula, to check the weight. Events of files without Xavg
+    // (null in a mixed run) are left out.
+    let lf = if settings.check_xavg && has_planes && schema.contains("Xavg") {
+        let all_valid = col("Xavg").neq(lit(sentinel)).and(col("X1").neq(lit(sentinel))).and(col("X2").neq(lit(sentinel)));
+        h.add_batch_hist1d("Xavg Provided - Computed", XAVG_CHECK_COLUMN, 2000, (-10.0, 10.0), None);
+        lf.with_column(
+            when(all_valid)
+                .then(col("Xavg") - weighted_xavg.clone())
+                .otherwise(lit(sentinel))
+                .alias(XAVG_CHECK_COLUMN)
+        )
+    } else {
+        lf
+    };
+
+    let computed_xavg = when(col("X1").neq(lit(sentinel)).and(col("X2").neq(lit(sentinel))))
+        .then(weighted_xavg)
+        .otherwise(lit(sentinel));
+    let lf = if settings.compute_xavg && has_planes && !schema.contains("Xavg") {
+        h.load_report.push(format!("Xavg is computed from X1 and X2 with an X1 weight of {}", settings.xavg_x1_weight));
+        lf.with_column(computed_xavg.alias("Xavg"))
+    } else if settings.compute_xavg && has_planes {
+        // In a run mixing files with and without Xavg, the events of the files without it have a null Xavg
+        lf.with_column(col("Xavg").fill_null(computed_xavg).alias("Xavg"))
+    } else {
+        lf
+    };
 
     // // create a new column
     // let lf = lf.with_columns(vec![
@@ -33,9 +492,9 @@ pub fn add_histograms(file_paths: Arc<[PathBuf]>) -> Result<Histogrammer, Polars
 
     //////////////////////////////////////////
 
-    let lf = lf.with_columns(vec![
-        (col("DelayFrontRightEnergy")+col("DelayFrontLeftEnergy")/ lit(2.0) ).alias("DelayFrontAverageEnergy"),
-        (col("DelayBackRightEnergy")+col("DelayBackLeftEnergy")/ lit(2.0) ).alias("DelayBackAverageEnergy"),
+    let lf = with_available_columns(lf, vec![
+        delay_average("DelayFrontLeftEnergy", "DelayFrontRightEnergy", settings.delay_average_mode, sentinel).alias("DelayFrontAverageEnergy"),
+        delay_average("DelayBackLeftEnergy", "DelayBackRightEnergy", settings.delay_average_mode, sentinel).alias("DelayBackAverageEnergy"),
         (col("DelayFrontLeftTime") - col("AnodeFrontTime")).alias("DelayFrontLeftTime_AnodeFrontTime"),
         (col("DelayFrontRightTime") - col("AnodeFrontTime")).alias("DelayFrontRightTime_AnodeFrontTime"),
         (col("DelayBackLeftTime") - col("AnodeFrontTime")).alias("DelayBackLeftTime_AnodeFrontTime"),
@@ -53,104 +512,167 @@ pub fn add_histograms(file_paths: Arc<[PathBuf]>) -> Result<Histogrammer, Polars
         (col("DelayBackLeftTime") - col("ScintLeftTime")).alias("DelayBackLeftTime_ScintLeftTime"),
         (col("DelayBackRightTime") - col("ScintLeftTime")).alias("DelayBackRightTime_ScintLeftTime"),
         (col("ScintRightTime") - col("ScintLeftTime")).alias("ScintRightTime_ScintLeftTime"),
-    ]);
-
-    h.add_fill_hist1d("X1", &lf, "X1", 600, (-300.0, 300.0));
-    h.add_fill_hist1d("X2", &lf, "X2", 600, (-300.0, 300.0));
-    h.add_fill_hist2d("X2 v X1", &lf, "X1", 600, (-300.0, 300.0), "X2", 600, (-300.0,300.0));
-    h.add_fill_hist2d("DelayBackRight v X1", &lf, "X1", 600, (-300.0, 300.0), "DelayBackRightEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayBackLeft v X1", &lf, "X1", 600, (-300.0, 300.0), "DelayBackLeftEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayFrontRight v X1", &lf, "X1", 600, (-300.0, 300.0), "DelayFrontRightEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayFrontLeft v X1", &lf, "X1", 600, (-300.0, 300.0), "DelayFrontLeftEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayBackRight v X2", &lf, "X2", 600, (-300.0, 300.0), "DelayBackRightEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayBackLeft v X2", &lf, "X2", 600, (-300.0, 300.0), "DelayBackLeftEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayFrontRight v X2", &lf, "X2", 600, (-300.0, 300.0), "DelayFrontRightEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayFrontLeft v X2", &lf, "X2", 600, (-300.0, 300.0), "DelayFrontLeftEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayBackRight v Xavg", &lf, "Xavg", 600, (-300.0, 300.0), "DelayBackRightEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayBackLeft v Xavg", &lf, "Xavg", 600, (-300.0, 300.0), "DelayBackLeftEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayFrontRight v Xavg", &lf, "Xavg", 600, (-300.0, 300.0), "DelayFrontRightEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayFrontLeft v Xavg", &lf, "Xavg", 600, (-300.0, 300.0), "DelayFrontLeftEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayFrontAverage v X1", &lf, "X1", 600, (-300.0, 300.0), "DelayFrontAverageEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayBackAverage v X1", &lf, "X1", 600, (-300.0, 300.0), "DelayBackAverageEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayFrontAverage v X2", &lf, "X2", 600, (-300.0, 300.0), "DelayFrontAverageEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayBackAverage v X2", &lf, "X2", 600, (-300.0, 300.0), "DelayBackAverageEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayFrontAverage v Xavg", &lf, "Xavg", 600, (-300.0, 300.0), "DelayFrontAverageEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("DelayBackAverage v Xavg", &lf, "Xavg", 600, (-300.0, 300.0), "DelayBackAverageEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("AnodeBack v ScintLeft", &lf, "ScintLeftEnergy", 512, (0.0, 4096.0), "AnodeBackEnergy", 512, (0.0, 4096.0));
-    h.add_fill_hist2d("AnodeFront v ScintLeft", &lf, "ScintLeftEnergy", 256, (0.0, 4096.0), "AnodeFrontEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("Cathode v ScintLeft", &lf, "ScintLeftEnergy", 256, (0.0, 4096.0), "CathodeEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("AnodeBack v ScintRight", &lf, "ScintRightEnergy", 256, (0.0, 4096.0), "AnodeBackEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("AnodeFront v ScintRight", &lf, "ScintRightEnergy", 256, (0.0, 4096.0), "AnodeFrontEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("Cathode v ScintRight", &lf, "ScintRightEnergy", 256, (0.0, 4096.0), "CathodeEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("ScintLeft v X1", &lf, "X1", 600, (-300.0, 300.0), "ScintLeftEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("ScintLeft v X2", &lf, "X2", 600, (-300.0, 300.0), "ScintLeftEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("ScintLeft v Xavg", &lf, "Xavg", 600, (-300.0, 300.0), "ScintLeftEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("ScintRight v X1", &lf, "X1", 600, (-300.0, 300.0), "ScintRightEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("ScintRight v X2", &lf, "X2", 600, (-300.0, 300.0), "ScintRightEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("ScintRight v Xavg", &lf, "Xavg", 600, (-300.0, 300.0), "ScintRightEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("AnodeBack v X1", &lf, "X1", 600, (-300.0, 300.0), "AnodeBackEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("AnodeBack v X2", &lf, "X2", 600, (-300.0, 300.0), "AnodeBackEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("AnodeBack v Xavg", &lf, "Xavg", 600, (-300.0, 300.0), "AnodeBackEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("AnodeFront v X1", &lf, "X1", 600, (-300.0, 300.0), "AnodeFrontEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("AnodeFront v X2", &lf, "X2", 600, (-300.0, 300.0), "AnodeFrontEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("AnodeFront v Xavg", &lf, "Xavg", 600, (-300.0, 300.0), "AnodeFrontEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("Cathode v X1", &lf, "X1", 600, (-300.0, 300.0), "CathodeEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("Cathode v X2", &lf, "X2", 600, (-300.0, 300.0), "CathodeEnergy", 256, (0.0, 4096.0));
-    h.add_fill_hist2d("Cathode v Xavg", &lf, "Xavg", 600, (-300.0, 300.0), "CathodeEnergy", 256, (0.0, 4096.0));
+    ])?;
 
-    // Both planes histograms
-    let lf_bothplanes = lf.clone().filter(col("X1").neq(lit(-1e6))).filter(col("X2").neq(lit(-1e6)));
+    // Theta is written in radians, the no value marker is kept as is
+    let (lf, theta_name, theta_range) = if settings.theta_degrees {
+        let lf = with_available_columns(lf, vec![
+            when(col("Theta").neq(lit(sentinel)))
+                .then(col("Theta") * lit(180.0 / PI))
+                .otherwise(lit(sentinel))
+                .alias("Theta"),
+        ])?;
+        (lf, "Theta (deg) v Xavg: bothplanes", (0.0, 90.0))
+    } else {
+        (lf, "Theta v Xavg: bothplanes", (0.0, PI / 2.0))
+    };
+
+    let lf = add_cebra_time_to_scint(&mut h, lf, settings, cebra_selection)?;
+
+    // Anode that has to be valid for the timing histograms
+    let reference_anode = settings.time_reference_anode.column();
 
-    h.add_fill_hist1d("X1: bothplanes", &lf_bothplanes, "X1", 600, (-300.0, 300.0));
-    h.add_fill_hist1d("X2: bothplanes", &lf_bothplanes, "X2", 600, (-300.0, 300.0));
-    h.add_fill_hist1d("Xavg: bothplanes", &lf_bothplanes, "Xavg", 600, (-300.0, 300.0));
+    // Event selections used by the histograms below. They are evaluated as boolean columns in the same pass as the fills.
+    let lf = with_available_columns(lf, vec![
+        match settings.both_planes {
+            BothPlanesDefinition::X1AndX2 => col("X1").neq(lit(sentinel)).and(col("X2").neq(lit(sentinel))),
+            BothPlanesDefinition::Xavg => col("Xavg").neq(lit(sentinel)),
+        }.alias("bothplanes"),
+        col("X1").neq(lit(sentinel)).and(col("X2").eq(lit(sentinel))).alias("only_x1_plane"),
+        col("X2").neq(lit(sentinel)).and(col("X1").eq(lit(sentinel))).alias("only_x2_plane"),
+        col(reference_anode).neq(lit(sentinel)).and(col("ScintLeftTime").neq(lit(sentinel))).alias("time_rel_anode"),
+    ])?;
 
-    h.add_fill_hist2d("Theta v Xavg: bothplanes", &lf_bothplanes, "Xavg", 600, (-300.0, 300.0), "Theta", 300, (0.0, PI/2.0));
-    // h.add_fill_hist1d("DelayFrontLeftTime_relTo_AnodeFrontTime_bothplanes", &lf_bothplanes, "DelayFrontLeftTime_AnodeFrontTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayFrontRightTime_relTo_AnodeFrontTime_bothplanes", &lf_bothplanes, "DelayFrontRightTime_AnodeFrontTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayBackLeftTime_relTo_AnodeBackTime_bothplanes", &lf_bothplanes, "DelayBackLeftTime_AnodeBackTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayBackRightTime_relTo_AnodeBackTime_bothplanes", &lf_bothplanes, "DelayBackRightTime_AnodeBackTime", 8000, (-4000.0, 4000.0));
+    // Xavg, or the plane that fired when there is no Xavg. The total of the two plane efficiency, Xavg alone
+    // only has the events where both planes fired.
+    let lf = with_available_columns(lf, vec![
+        when(col("Xavg").neq(lit(sentinel))).then(col("Xavg"))
+            .when(col("X1").neq(lit(sentinel))).then(col("X1"))
+            .otherwise(col("X2"))
+            .alias(ANY_PLANE_POSITION_COLUMN),
+    ])?;
+
+    // Gate every histogram with the cuts, now that the computed columns (Xavg, Theta, time differences) exist.
+    // A cut on a time column is in the calibrated units it was drawn in. With live recalibration the events
+    // in the cut stay those of the calibration at build time.
+    // An event is kept if it is inside one of the cuts of every handler.
+    let lf = cuts.iter().fold(lf, |lf, cutter| cutter.filter_lf_with_cuts(&lf, sentinel, &|column| settings.calibrated_column(column)));
+
+    h.add_batch_hist1d("X1", "X1", 600, (-300.0, 300.0), None);
+    h.add_batch_hist1d("X2", "X2", 600, (-300.0, 300.0), None);
+    h.add_batch_hist1d("Xavg: anyplane", ANY_PLANE_POSITION_COLUMN, 600, (-300.0, 300.0), None); // all events, for the two plane efficiency
+    h.add_batch_hist2d("X2 v X1", "X1", 600, (-300.0, 300.0), "X2", 600, (-300.0,300.0), None);
+    h.add_batch_hist2d("DelayBackRight v X1", "X1", 600, (-300.0, 300.0), "DelayBackRightEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayBackLeft v X1", "X1", 600, (-300.0, 300.0), "DelayBackLeftEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayFrontRight v X1", "X1", 600, (-300.0, 300.0), "DelayFrontRightEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayFrontLeft v X1", "X1", 600, (-300.0, 300.0), "DelayFrontLeftEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayBackRight v X2", "X2", 600, (-300.0, 300.0), "DelayBackRightEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayBackLeft v X2", "X2", 600, (-300.0, 300.0), "DelayBackLeftEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayFrontRight v X2", "X2", 600, (-300.0, 300.0), "DelayFrontRightEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayFrontLeft v X2", "X2", 600, (-300.0, 300.0), "DelayFrontLeftEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayBackRight v Xavg", "Xavg", 600, (-300.0, 300.0), "DelayBackRightEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayBackLeft v Xavg", "Xavg", 600, (-300.0, 300.0), "DelayBackLeftEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayFrontRight v Xavg", "Xavg", 600, (-300.0, 300.0), "DelayFrontRightEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayFrontLeft v Xavg", "Xavg", 600, (-300.0, 300.0), "DelayFrontLeftEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayFrontAverage v X1", "X1", 600, (-300.0, 300.0), "DelayFrontAverageEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayBackAverage v X1", "X1", 600, (-300.0, 300.0), "DelayBackAverageEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayFrontAverage v X2", "X2", 600, (-300.0, 300.0), "DelayFrontAverageEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayBackAverage v X2", "X2", 600, (-300.0, 300.0), "DelayBackAverageEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayFrontAverage v Xavg", "Xavg", 600, (-300.0, 300.0), "DelayFrontAverageEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("DelayBackAverage v Xavg", "Xavg", 600, (-300.0, 300.0), "DelayBackAverageEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("AnodeBack v ScintLeft", "ScintLeftEnergy", 512, (0.0, 4096.0), "AnodeBackEnergy", 512, (0.0, 4096.0), None);
+    h.add_batch_hist2d("AnodeFront v ScintLeft", "ScintLeftEnergy", 256, (0.0, 4096.0), "AnodeFrontEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("Cathode v ScintLeft", "ScintLeftEnergy", 256, (0.0, 4096.0), "CathodeEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("AnodeBack v ScintRight", "ScintRightEnergy", 256, (0.0, 4096.0), "AnodeBackEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("AnodeFront v ScintRight", "ScintRightEnergy", 256, (0.0, 4096.0), "AnodeFrontEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("Cathode v ScintRight", "ScintRightEnergy", 256, (0.0, 4096.0), "CathodeEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("ScintLeft v X1", "X1", 600, (-300.0, 300.0), "ScintLeftEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("ScintLeft v X2", "X2", 600, (-300.0, 300.0), "ScintLeftEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("ScintLeft v Xavg", "Xavg", 600, (-300.0, 300.0), "ScintLeftEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("ScintRight v X1", "X1", 600, (-300.0, 300.0), "ScintRightEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("ScintRight v X2", "X2", 600, (-300.0, 300.0), "ScintRightEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("ScintRight v Xavg", "Xavg", 600, (-300.0, 300.0), "ScintRightEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("AnodeBack v X1", "X1", 600, (-300.0, 300.0), "AnodeBackEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("AnodeBack v X2", "X2", 600, (-300.0, 300.0), "AnodeBackEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("AnodeBack v Xavg", "Xavg", 600, (-300.0, 300.0), "AnodeBackEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("AnodeFront v X1", "X1", 600, (-300.0, 300.0), "AnodeFrontEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("AnodeFront v X2", "X2", 600, (-300.0, 300.0), "AnodeFrontEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("AnodeFront v Xavg", "Xavg", 600, (-300.0, 300.0), "AnodeFrontEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("Cathode v X1", "X1", 600, (-300.0, 300.0), "CathodeEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("Cathode v X2", "X2", 600, (-300.0, 300.0), "CathodeEnergy", 256, (0.0, 4096.0), None);
+    h.add_batch_hist2d("Cathode v Xavg", "Xavg", 600, (-300.0, 300.0), "CathodeEnergy", 256, (0.0, 4096.0), None);
+
+    // Both planes histograms
+    h.add_batch_hist1d("X1: bothplanes", "X1", 600, (-300.0, 300.0), Some("bothplanes"));
+    h.add_batch_hist1d("X2: bothplanes", "X2", 600, (-300.0, 300.0), Some("bothplanes"));
+    h.add_batch_hist1d("Xavg: bothplanes", "Xavg", 600, (-300.0, 300.0), Some("bothplanes"));
+
+    h.add_batch_hist2d(theta_name, "Xavg", 600, (-300.0, 300.0), "Theta", 300, theta_range, Some("bothplanes"));
+    // h.add_batch_hist1d("DelayFrontLeftTime_relTo_AnodeFrontTime_bothplanes", "DelayFrontLeftTime_AnodeFrontTime", 8000, (-4000.0, 4000.0), Some("bothplanes"));
+    // h.add_batch_hist1d("DelayFrontRightTime_relTo_AnodeFrontTime_bothplanes", "DelayFrontRightTime_AnodeFrontTime", 8000, (-4000.0, 4000.0), Some("bothplanes"));
+    // h.add_batch_hist1d("DelayBackLeftTime_relTo_AnodeBackTime_bothplanes", "DelayBackLeftTime_AnodeBackTime", 8000, (-4000.0, 4000.0), Some("bothplanes"));
+    // h.add_batch_hist1d("DelayBackRightTime_relTo_AnodeBackTime_bothplanes", "DelayBackRightTime_AnodeBackTime", 8000, (-4000.0, 4000.0), Some("bothplanes"));
     
     // Only 1 plane: X1
-    let lf_only_x1_plane = lf.clone().filter(col("X1").neq(lit(-1e6))).filter(col("X2").eq(lit(-1e6)));
-
-    h.add_fill_hist1d("X1: only1plane", &lf_only_x1_plane, "X1", 600, (-300.0, 300.0));
-    // h.add_fill_hist1d("DelayFrontLeftTime_relTo_AnodeFrontTime_noX2", &lf_only_x1_plane, "DelayFrontLeftTime_AnodeFrontTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayFrontRightTime_relTo_AnodeFrontTime_noX2", &lf_only_x1_plane, "DelayFrontRightTime_AnodeFrontTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayBackLeftTime_relTo_AnodeFrontTime_noX2", &lf_only_x1_plane, "DelayBackLeftTime_AnodeFrontTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayBackRightTime_relTo_AnodeFrontTime_noX2", &lf_only_x1_plane, "DelayBackRightTime_AnodeFrontTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayFrontLeftTime_relTo_AnodeBackTime_noX2", &lf_only_x1_plane, "DelayFrontLeftTime_AnodeBackTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayFrontRightTime_relTo_AnodeBackTime_noX2", &lf_only_x1_plane, "DelayFrontRightTime_AnodeBackTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayBackLeftTime_relTo_AnodeBackTime_noX2", &lf_only_x1_plane, "DelayBackLeftTime_AnodeBackTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayBackRightTime_relTo_AnodeBackTime_noX2", &lf_only_x1_plane, "DelayBackRightTime_AnodeBackTime", 8000, (-4000.0, 4000.0));
+    h.add_batch_hist1d("X1: only1plane", "X1", 600, (-300.0, 300.0), Some("only_x1_plane"));
+    // h.add_batch_hist1d("DelayFrontLeftTime_relTo_AnodeFrontTime_noX2", "DelayFrontLeftTime_AnodeFrontTime", 8000, (-4000.0, 4000.0), Some("only_x1_plane"));
+    // h.add_batch_hist1d("DelayFrontRightTime_relTo_AnodeFrontTime_noX2", "DelayFrontRightTime_AnodeFrontTime", 8000, (-4000.0, 4000.0), Some("only_x1_plane"));
+    // h.add_batch_hist1d("DelayBackLeftTime_relTo_AnodeFrontTime_noX2", "DelayBackLeftTime_AnodeFrontTime", 8000, (-4000.0, 4000.0), Some("only_x1_plane"));
+    // h.add_batch_hist1d("DelayBackRightTime_relTo_AnodeFrontTime_noX2", "DelayBackRightTime_AnodeFrontTime", 8000, (-4000.0, 4000.0), Some("only_x1_plane"));
+    // h.add_batch_hist1d("DelayFrontLeftTime_relTo_AnodeBackTime_noX2", "DelayFrontLeftTime_AnodeBackTime", 8000, (-4000.0, 4000.0), Some("only_x1_plane"));
+    // h.add_batch_hist1d("DelayFrontRightTime_relTo_AnodeBackTime_noX2", "DelayFrontRightTime_AnodeBackTime", 8000, (-4000.0, 4000.0), Some("only_x1_plane"));
+    // h.add_batch_hist1d("DelayBackLeftTime_relTo_AnodeBackTime_noX2", "DelayBackLeftTime_AnodeBackTime", 8000, (-4000.0, 4000.0), Some("only_x1_plane"));
+    // h.add_batch_hist1d("DelayBackRightTime_relTo_AnodeBackTime_noX2", "DelayBackRightTime_AnodeBackTime", 8000, (-4000.0, 4000.0), Some("only_x1_plane"));
 
     // Only 1 plane: X2
-    let lf_only_x2_plane = lf.clone().filter(col("X2").neq(lit(-1e6))).filter(col("X1").eq(lit(-1e6)));
-
-    h.add_fill_hist1d("X2: only1plane", &lf_only_x2_plane, "X2", 600, (-300.0, 300.0));
-    // h.add_fill_hist1d("DelayFrontLeftTime_relTo_AnodeFrontTime_noX1", &lf_only_x2_plane, "DelayFrontLeftTime_AnodeFrontTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayFrontRightTime_relTo_AnodeFrontTime_noX1", &lf_only_x2_plane, "DelayFrontRightTime_AnodeFrontTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayBackLeftTime_relTo_AnodeFrontTime_noX1", &lf_only_x2_plane, "DelayBackLeftTime_AnodeFrontTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayBackRightTime_relTo_AnodeFrontTime_noX1", &lf_only_x2_plane, "DelayBackRightTime_AnodeFrontTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayFrontLeftTime_relTo_AnodeBackTime_noX1", &lf_only_x2_plane, "DelayFrontLeftTime_AnodeBackTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayFrontRightTime_relTo_AnodeBackTime_noX1", &lf_only_x2_plane, "DelayFrontRightTime_AnodeBackTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayBackLeftTime_relTo_AnodeBackTime_noX1", &lf_only_x2_plane, "DelayBackLeftTime_AnodeBackTime", 8000, (-4000.0, 4000.0));
-    // h.add_fill_hist1d("DelayBackRightTime_relTo_AnodeBackTime_noX1", &lf_only_x2_plane, "DelayBackRightTime_AnodeBackTime", 8000, (-4000.0, 4000.0));
-
-    // Time relative to Back Anode
-
-    let lf_time_rel_backanode = lf.clone().filter(col("AnodeBackTime").neq(lit(-1e6))).filter(col("ScintLeftTime").neq(lit(-1e6)));
-
-    h.add_fill_hist1d("AnodeFrontTime-AnodeBackTime", &lf_time_rel_backanode, "AnodeFrontTime_AnodeBackTime", 1000, (-3000.0 ,3000.0));
-    h.add_fill_hist1d("AnodeBackTime-AnodeFrontTime", &lf_time_rel_backanode, "AnodeBackTime_AnodeFrontTime", 1000, (-3000.0 ,3000.0));
-    h.add_fill_hist1d("AnodeFrontTime-ScintLeftTime", &lf_time_rel_backanode, "AnodeFrontTime_ScintLeftTime", 1000, (-3000.0 ,3000.0));
-    h.add_fill_hist1d("AnodeBackTime-ScintLeftTime", &lf_time_rel_backanode, "AnodeBackTime_ScintLeftTime", 1000, (-3000.0 ,3000.0));
-    h.add_fill_hist1d("DelayFrontLeftTime-ScintLeftTime", &lf_time_rel_backanode, "DelayFrontLeftTime_ScintLeftTime", 1000, (-3000.0 ,3000.0));
-    h.add_fill_hist1d("DelayFrontRightTime-ScintLeftTime", &lf_time_rel_backanode, "DelayFrontRightTime_ScintLeftTime", 1000, (-3000.0 ,3000.0));
-    h.add_fill_hist1d("DelayBackLeftTime-ScintLeftTime", &lf_time_rel_backanode, "DelayBackLeftTime_ScintLeftTime", 1000, (-3000.0 ,3000.0));
-    h.add_fill_hist1d("DelayBackRightTime-ScintLeftTime", &lf_time_rel_backanode, "DelayBackRightTime_ScintLeftTime", 1000, (-3000.0 ,3000.0));
-    h.add_fill_hist1d("ScintRightTime-ScintLeftTime", &lf_time_rel_backanode, "ScintRightTime_ScintLeftTime", 1000, (-3000.0 ,3000.0));
-    h.add_fill_hist2d("ScintTimeDif v Xavg", &lf_time_rel_backanode, "Xavg", 600, (-300.0, 300.0), "ScintRightTime_ScintLeftTime", 12800, (-3200.0, 3200.0));
+    h.add_batch_hist1d("X2: only1plane", "X2", 600, (-300.0, 300.0), Some("only_x2_plane"));
+    // h.add_batch_hist1d("DelayFrontLeftTime_relTo_AnodeFrontTime_noX1", "DelayFrontLeftTime_AnodeFrontTime", 8000, (-4000.0, 4000.0), Some("only_x2_plane"));
+    // h.add_batch_hist1d("DelayFrontRightTime_relTo_AnodeFrontTime_noX1", "DelayFrontRightTime_AnodeFrontTime", 8000, (-4000.0, 4000.0), Some("only_x2_plane"));
+    // h.add_batch_hist1d("DelayBackLeftTime_relTo_AnodeFrontTime_noX1", "DelayBackLeftTime_AnodeFrontTime", 8000, (-4000.0, 4000.0), Some("only_x2_plane"));
+    // h.add_batch_hist1d("DelayBackRightTime_relTo_AnodeFrontTime_noX1", "DelayBackRightTime_AnodeFrontTime", 8000, (-4000.0, 4000.0), Some("only_x2_plane"));
+    // h.add_batch_hist1d("DelayFrontLeftTime_relTo_AnodeBackTime_noX1", "DelayFrontLeftTime_AnodeBackTime", 8000, (-4000.0, 4000.0), Some("only_x2_plane"));
+    // h.add_batch_hist1d("DelayFrontRightTime_relTo_AnodeBackTime_noX1", "DelayFrontRightTime_AnodeBackTime", 8000, (-4000.0, 4000.0), Some("only_x2_plane"));
+    // h.add_batch_hist1d("DelayBackLeftTime_relTo_AnodeBackTime_noX1", "DelayBackLeftTime_AnodeBackTime", 8000, (-4000.0, 4000.0), Some("only_x2_plane"));
+    // h.add_batch_hist1d("DelayBackRightTime_relTo_AnodeBackTime_noX1", "DelayBackRightTime_AnodeBackTime", 8000, (-4000.0, 4000.0), Some("only_x2_plane"));
+
+    // Time relative to the reference anode
+    h.add_batch_hist1d("AnodeFrontTime-AnodeBackTime", "AnodeFrontTime_AnodeBackTime", 1000, settings.time_range("AnodeFrontTime_AnodeBackTime", (-3000.0 ,3000.0)), Some("time_rel_anode"));
+    h.add_batch_hist1d("AnodeBackTime-AnodeFrontTime", "AnodeBackTime_AnodeFrontTime", 1000, settings.time_range("AnodeBackTime_AnodeFrontTime", (-3000.0 ,3000.0)), Some("time_rel_anode"));
+    h.add_batch_hist1d("AnodeFrontTime-ScintLeftTime", "AnodeFrontTime_ScintLeftTime", 1000, settings.time_range("AnodeFrontTime_ScintLeftTime", (-3000.0 ,3000.0)), Some("time_rel_anode"));
+    h.add_batch_hist1d("AnodeBackTime-ScintLeftTime", "AnodeBackTime_ScintLeftTime", 1000, settings.time_range("AnodeBackTime_ScintLeftTime", (-3000.0 ,3000.0)), Some("time_rel_anode"));
+    h.add_batch_hist1d("DelayFrontLeftTime-ScintLeftTime", "DelayFrontLeftTime_ScintLeftTime", 1000, settings.time_range("DelayFrontLeftTime_ScintLeftTime", (-3000.0 ,3000.0)), Some("time_rel_anode"));
+    h.add_batch_hist1d("DelayFrontRightTime-ScintLeftTime", "DelayFrontRightTime_ScintLeftTime", 1000, settings.time_range("DelayFrontRightTime_ScintLeftTime", (-3000.0 ,3000.0)), Some("time_rel_anode"));
+    h.add_batch_hist1d("DelayBackLeftTime-ScintLeftTime", "DelayBackLeftTime_ScintLeftTime", 1000, settings.time_range("DelayBackLeftTime_ScintLeftTime", (-3000.0 ,3000.0)), Some("time_rel_anode"));
+    h.add_batch_hist1d("DelayBackRightTime-ScintLeftTime", "DelayBackRightTime_ScintLeftTime", 1000, settings.time_range("DelayBackRightTime_ScintLeftTime", (-3000.0 ,3000.0)), Some("time_rel_anode"));
+    h.add_batch_hist1d("ScintRightTime-ScintLeftTime", "ScintRightTime_ScintLeftTime", 1000, settings.time_range("ScintRightTime_ScintLeftTime", (-3000.0 ,3000.0)), Some("time_rel_anode"));
+    h.add_batch_hist2d("ScintTimeDif v Xavg", "Xavg", 600, (-300.0, 300.0), "ScintRightTime_ScintLeftTime", 12800, settings.time_range("ScintRightTime_ScintLeftTime", (-3200.0, 3200.0)), Some("time_rel_anode"));
+
+    for delay in ["DelayFrontLeftTime", "DelayFrontRightTime", "DelayBackLeftTime", "DelayBackRightTime"] {
+        let column = format!("{}_{}", delay, reference_anode);
+        h.add_batch_hist1d(&format!("{}-{}", delay, reference_anode), &column, 1000, settings.time_range(&column, (-3000.0 ,3000.0)), Some("time_rel_anode"));
+    }
+
+    // Read all of the columns once and fill every histogram
+    if let Some(names) = only {
+        h.keep_only(names);
+    }
+
+    // The raw time columns are kept before they are calibrated
+    if settings.live_recalibration && only.is_none() {
+        check_cancel(cancel)?;
+        h.recalibration = recalibration_cache(&h, &lf, settings)?;
+    }
+
+    // Convert the time columns to calibrated units (e.g. ns) where requested
+    let lf = with_available_columns(lf, settings.time_calibration_expressions())?;
+
+    h.fill_batch(&lf, progress, cancel)?;
+
+    // Run progression
+    if only.is_none() {
+        check_cancel(cancel)?;
+        add_run_progression(&mut h, &lf, settings, cancel)?;
+    }
 
     Ok(h)
 }
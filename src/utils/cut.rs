@@ -1,20 +1,38 @@
-use crate::utils::egui_polygon::EditableEguiPolygon;
+use crate::utils::egui_polygon::{EditableEguiPolygon, CUT_COLORS};
+use crate::utils::settings::Settings;
 
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::ffi::OsStr;
 
 use rfd::FileDialog;
+use geo::{Contains, Point};
 use egui_plot::PlotUi;
 use polars::prelude::*;
 
+// Temporary column with the cut mask while filtering
+const CUT_MASK_COLUMN: &str = "InCuts";
+
+// What to do when a file saved separately already exists
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExistingFileAction {
+    Overwrite,
+    Skip,
+    Rename, // append _1, _2, ... to the name
+}
+
+#[derive(Clone)]
 pub struct CutHandler {
     pub cuts: HashMap<String, EditableEguiPolygon>,
     pub active_cut_id: Option<String>,
     pub draw_flag: bool,
     pub save_option: String,
     pub save_seperate_suffix: String,
+    pub existing_file_action: ExistingFileAction,
+    pub rebuild_requested: bool,
+    pub show_all_cuts: bool, // draw the other cuts too, not only the active one
+    pub save_report: Vec<String>, // files overwritten, skipped or renamed and errors of the last save
 }
 
 impl CutHandler {
@@ -26,18 +44,28 @@ impl CutHandler {
             draw_flag: true,
             save_option: "separate".to_string(),
             save_seperate_suffix : "filtered".to_string(), // Default suffix for separate save option
+            existing_file_action: ExistingFileAction::Rename,
+            rebuild_requested: false,
+            show_all_cuts: false,
+            save_report: Vec::new(),
         }
     }
 
     // Adds a new cut and makes it the active one
     pub fn add_new_cut(&mut self) {
         let new_id = format!("cut_{}", self.cuts.len() + 1);
-        self.cuts.insert(new_id.clone(), EditableEguiPolygon::new());
+        let color = CUT_COLORS[self.cuts.len() % CUT_COLORS.len()];
+        self.cuts.insert(new_id.clone(), EditableEguiPolygon::with_color(color));
         self.active_cut_id = Some(new_id); // Automatically make the new cut active
     }
 
+    // True if at least one cut has its columns selected and encloses an area
+    pub fn has_valid_cuts(&self) -> bool {
+        self.cuts.values().any(|cut| cut.selected_x_column.is_some() && cut.selected_y_column.is_some() && cut.vertices.len() >= 3)
+    }
+
     // UI handler for the cut handler.
-    pub fn cut_handler_ui(&mut self, ui: &mut egui::Ui, file_paths: Arc<[PathBuf]>) {
+    pub fn cut_handler_ui(&mut self, ui: &mut egui::Ui, file_paths: Arc<[PathBuf]>, settings: &Settings) {
         ui.horizontal(|ui| {
             ui.label("2D Cutter");
             ui.separator();
@@ -69,9 +97,18 @@ impl CutHandler {
                     ui.label("Suffix: ")
                         .on_hover_text("Custom suffix to append to the original file name when saving separately");
                     ui.text_edit_singleline(&mut self.save_seperate_suffix);
+
+                    ui.label("If exists: ")
+                        .on_hover_text("What to do when an output file already exists");
+                    ui.radio_value(&mut self.existing_file_action, ExistingFileAction::Rename, "Rename");
+                    ui.radio_value(&mut self.existing_file_action, ExistingFileAction::Skip, "Skip");
+                    ui.radio_value(&mut self.existing_file_action, ExistingFileAction::Overwrite, "Overwrite");
                 }
 
                 if ui.button("Save").clicked() {
+                    // The save may be the first use of polars
+                    settings.apply_thread_count();
+                    self.save_report.clear();
 
                     // Depending on the save option, call the appropriate method
                     match self.save_option.as_str() {
@@ -83,8 +120,8 @@ impl CutHandler {
                             .save_file() {
 
                                 // Call the method to save all filtered dataframes into one file
-                                if let Err(e) = self.filter_files_and_save_to_one_file(file_paths.clone(), &path) {
-                                    eprintln!("Failed to save DataFrame: {:?}", e);
+                                if let Err(e) = self.filter_files_and_save_to_one_file(file_paths.clone(), &path, settings.scan_args(), settings.sentinel) {
+                                    self.save_report.push(format!("Failed to save DataFrame: {}", e));
                                 }
 
                             }
@@ -97,8 +134,8 @@ impl CutHandler {
                                 let suffix = self.save_seperate_suffix.clone();
                 
                                 // Assuming filter_files_and_save_separately expects a directory path and suffix
-                                if let Err(e) = self.filter_files_and_save_separately(file_paths.clone(), &directory_path, &suffix) {
-                                    eprintln!("Failed to save DataFrames separately: {:?}", e);
+                                if let Err(e) = self.filter_files_and_save_separately(file_paths.clone(), &directory_path, &suffix, settings.scan_args(), settings.sentinel) {
+                                    self.save_report.push(format!("Failed to save DataFrames separately: {}", e));
                                 }
                             }
                         },
@@ -110,6 +147,12 @@ impl CutHandler {
 
             ui.separator();
 
+            if ui.add_enabled(self.has_valid_cuts(), egui::Button::new("Apply Cuts and Rebuild"))
+                .on_hover_text("Rebuild every histogram from the selected files using only the events inside the cuts")
+                .on_disabled_hover_text("Draw a cut and select its columns first")
+                .clicked() {
+                self.rebuild_requested = true;
+            }
 
         });
 
@@ -122,9 +165,8 @@ impl CutHandler {
                     .selected_text(&selected_label)
                     .show_ui(ui, |ui| {
                         ui.selectable_value(&mut self.active_cut_id, None, "None"); // Option to deselect any active cut
-                        for (id, _) in self.cuts.iter() {
-                            let label = format!("{}", id);
-                            ui.selectable_value(&mut self.active_cut_id, Some(id.clone()), &label);
+                        for id in self.cuts.keys() {
+                            ui.selectable_value(&mut self.active_cut_id, Some(id.clone()), id);
                         }
                     });
             }
@@ -141,35 +183,121 @@ impl CutHandler {
                 ui.checkbox(&mut self.draw_flag, "Draw");
             }
 
+            if self.cuts.len() > 1 {
+                ui.checkbox(&mut self.show_all_cuts, "Show All Cuts")
+                    .on_hover_text("Also draw the other cuts on the same columns as the active cut or the plotted histogram, dimmed in their own colors. Only the active cut can be edited.");
+            }
+
         });
+
+        if !self.save_report.is_empty() {
+            egui::CollapsingHeader::new(format!("Save Report ({})", self.save_report.len())).show(ui, |ui| {
+                for line in self.save_report.iter() {
+                    ui.colored_label(ui.visuals().warn_fg_color, line);
+                }
+            });
+        }
     }
 
-    // Method to draw the active cut
-    pub fn draw_active_cut(&mut self, plot_ui: &mut PlotUi) {
-        if self.draw_flag {
-            if let Some(active_id) = &self.active_cut_id {
-                if let Some(active_cut) = self.cuts.get_mut(active_id) {
+    // Get the active cut, if there is one
+    pub fn active_cut(&self) -> Option<&EditableEguiPolygon> {
+        self.active_cut_id.as_ref().and_then(|id| self.cuts.get(id))
+    }
+
+    // Draws the active cut, which can be edited if `editable`, and with "Show All Cuts" the outlines of the
+    // others, which can't. Those are only drawn if they are on the columns of the active cut or of a plotted
+    // histogram (x, y). The active cut is drawn last so it stays on top. Nothing is drawn without draw_flag.
+    pub fn draw_cuts(&mut self, plot_ui: &mut PlotUi, editable: bool, plotted_columns: &[(String, String)]) {
+        if !self.draw_flag {
+            return;
+        }
+
+        if self.show_all_cuts {
+            let active_columns = self.active_cut().map(|cut| (cut.selected_x_column.clone(), cut.selected_y_column.clone()));
+            for (id, cut) in self.cuts.iter() {
+                if self.active_cut_id.as_ref() == Some(id) {
+                    continue;
+                }
+
+                let (Some(x_column), Some(y_column)) = (&cut.selected_x_column, &cut.selected_y_column) else {
+                    continue;
+                };
+                let on_active_columns = active_columns.as_ref()
+                    .is_some_and(|(x, y)| x.as_ref() == Some(x_column) && y.as_ref() == Some(y_column));
+                let on_plotted_columns = plotted_columns.iter().any(|(x, y)| x == x_column && y == y_column);
+                if on_active_columns || on_plotted_columns {
+                    cut.draw_outline(plot_ui);
+                }
+            }
+        }
+
+        if let Some(active_id) = &self.active_cut_id {
+            if let Some(active_cut) = self.cuts.get_mut(active_id) {
+                if editable {
                     active_cut.draw(plot_ui);
+                } else {
+                    active_cut.draw_vertices_and_polygon(plot_ui);
                 }
             }
         }
     }
 
-    pub fn filter_files_and_save_to_one_file(&mut self, file_paths: Arc<[PathBuf]>, output_path: &PathBuf) -> Result<(), PolarsError> {
-        let args = ScanArgsParquet::default();
+    // Output path for a separately saved file according to the existing file option. None means skip the file.
+    fn resolve_output_path(&mut self, output_file_path: PathBuf) -> Option<PathBuf> {
+        if !output_file_path.exists() {
+            return Some(output_file_path);
+        }
+
+        match self.existing_file_action {
+            ExistingFileAction::Overwrite => {
+                self.save_report.push(format!("Overwriting {}", output_file_path.display()));
+                Some(output_file_path)
+            }
+            ExistingFileAction::Skip => {
+                self.save_report.push(format!("Skipping {} (already exists)", output_file_path.display()));
+                None
+            }
+            ExistingFileAction::Rename => {
+                let stem = output_file_path.file_stem().unwrap_or(OsStr::new("default")).to_string_lossy().to_string();
+                let renamed_path = (1..)
+                    .map(|i| output_file_path.with_file_name(format!("{}_{}.parquet", stem, i)))
+                    .find(|path| !path.exists())?;
+                self.save_report.push(format!("{} already exists, saving to {}", output_file_path.display(), renamed_path.display()));
+                Some(renamed_path)
+            }
+        }
+    }
+
+    // Filters lf with the cuts for saving. The saved files must have the same columns, in the same order
+    // and with the same types, as the files they came from, since the tools reading them expect the full
+    // schema. That is checked here so a mismatch is an error instead of a silently different file.
+    fn collect_filtered_for_saving(&self, lf: &LazyFrame, sentinel: f64) -> Result<DataFrame, PolarsError> {
+        let input_schema = lf.schema()?;
+        let filtered_df = self.filter_lf_with_cuts(lf, sentinel, &col).collect()?;
+
+        if filtered_df.schema() != *input_schema {
+            return Err(PolarsError::SchemaMismatch("the filtered events don't have the columns of the input files".into()));
+        }
+
+        Ok(filtered_df)
+    }
 
+    // The save dialog asks before replacing an existing file, so the single file is always written.
+    // The output keeps every column and the order of the events (see collect_filtered_for_saving).
+    pub fn filter_files_and_save_to_one_file(&mut self, file_paths: Arc<[PathBuf]>, output_path: &PathBuf, args: ScanArgsParquet, sentinel: f64) -> Result<(), PolarsError> {
         // Assuming LazyFrame::scan_parquet_files constructs a LazyFrame from the list of files
         let lf = LazyFrame::scan_parquet_files(file_paths, args)?;
 
-        // Apply filtering logic as before, leading to a filtered LazyFrame
-        let filtered_lf = self.filter_lf_with_cuts(&lf)?; // Placeholder for applying cuts
+        // Apply filtering logic as before, leading to a filtered DataFrame with every column
+        let mut filtered_df = self.collect_filtered_for_saving(&lf, sentinel)?;
 
-        // Collect the LazyFrame into a DataFrame
-        let mut filtered_df = filtered_lf.collect()?;
+        if output_path.exists() {
+            self.save_report.push(format!("Overwriting {}", output_path.display()));
+        }
 
         // Open a file in write mode at the specified output path
         let file = File::create(output_path)
-            .map_err(|e| PolarsError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+            .map_err(|e| PolarsError::Io(std::io::Error::other(e)))?;
 
         // Write the filtered DataFrame to a Parquet file
         ParquetWriter::new(file)
@@ -179,27 +307,26 @@ impl CutHandler {
         Ok(())
     }
 
-    pub fn filter_files_and_save_separately(&mut self, file_paths: Arc<[PathBuf]>, output_dir: &PathBuf, custom_text: &str) -> Result<(), PolarsError> {
-        let args = ScanArgsParquet::default();
-    
+    // Each output file keeps every column and the order of the events of its input file.
+    pub fn filter_files_and_save_separately(&mut self, file_paths: Arc<[PathBuf]>, output_dir: &Path, custom_text: &str, args: ScanArgsParquet, sentinel: f64) -> Result<(), PolarsError> {
         for file_path in file_paths.iter() {
-            // Construct a LazyFrame for each file
-            let lf = LazyFrame::scan_parquet(file_path, args.clone())?;
-    
-            // Apply filtering logic as before, leading to a filtered LazyFrame
-            let filtered_lf = self.filter_lf_with_cuts(&lf)?; // Placeholder for applying cuts
-    
-            // Collect the LazyFrame into a DataFrame
-            let mut filtered_df = filtered_lf.collect()?;
-    
             // Generate a new output file name by appending custom text to the original file name
             let original_file_name = file_path.file_stem().unwrap_or(OsStr::new("default"));
             let new_file_name = format!("{}_{}.parquet", original_file_name.to_string_lossy(), custom_text);
-            let output_file_path = output_dir.join(new_file_name);
+
+            let Some(output_file_path) = self.resolve_output_path(output_dir.join(new_file_name)) else {
+                continue;
+            };
+
+            // Construct a LazyFrame for each file
+            let lf = LazyFrame::scan_parquet(file_path, args.clone())?;
+    
+            // Apply filtering logic as before, leading to a filtered DataFrame with every column
+            let mut filtered_df = self.collect_filtered_for_saving(&lf, sentinel)?;
 
             // Open a file in write mode at the newly specified output path
             let file = File::create(&output_file_path)
-                .map_err(|e| PolarsError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+                .map_err(|e| PolarsError::Io(std::io::Error::other(e)))?;
 
             // Write the filtered DataFrame to a new Parquet file
             ParquetWriter::new(file)
@@ -210,80 +337,106 @@ impl CutHandler {
         Ok(())
     }
 
-    pub fn filter_lf_with_cuts(&mut self, lf: &LazyFrame) -> Result<LazyFrame, PolarsError> {
-
-        // this is a lot of work to filter the lazy frame with the cuts but it works
-        let filtered_lf = lf.clone();
-
-        // Iterate through the cuts, get column names, and filter the lazy frame with the null values (-1e6) first before collecting
-        for (_id, cut) in self.cuts.iter() {
+    // Mask of the events inside any of the cuts, false for all events if no cut has its columns selected.
+    // Events where a cut column has the sentinel (no value) or is null are never inside that cut.
+    fn cut_mask(&self, sentinel: f64, column: &dyn Fn(&str) -> Expr) -> Expr {
+        let mut mask = lit(false);
+        for cut in self.cuts.values() {
             if let (Some(x_col_name), Some(y_col_name)) = (&cut.selected_x_column, &cut.selected_y_column) {
-                let _filtered_lf = filtered_lf.clone()
-                    .filter(col(x_col_name).neq(lit(-1e6)))
-                    .filter(col(y_col_name).neq(lit(-1e6)));
+                mask = mask.or(polygon_mask(cut, column(x_col_name), column(y_col_name), sentinel));
             }
         }
+        mask
+    }
 
-        // Vector to store the masks for each cut
-        let mut masks: Vec<Vec<bool>> = Vec::new();
-
-        // Iterate through the cuts, get column names, collect columns, convert to ndarray, 
-        // check if the point is inside the polygon, and then create a mask
-        for (_id, cut) in self.cuts.iter() {
-
-            if let (Some(x_col_name), Some(y_col_name)) = (&cut.selected_x_column, &cut.selected_y_column) {
-                let mask_creation_df = filtered_lf.clone()
-                            .select([col(x_col_name), col(y_col_name)])
-                            .collect()?;
-
-                let ndarray_mask_creation_df = mask_creation_df.to_ndarray::<Float64Type>(IndexOrder::Fortran)?;
-                
-                let shape = ndarray_mask_creation_df.shape();
-                let rows = shape[0];
-
-                let mut mask: Vec<bool> = Vec::new();
-
-                // Iterating through the ndarray rows and check if the point is inside the polygon
-                for i in 0..rows {
-                    let x_value = ndarray_mask_creation_df[[i, 0]];
-                    let y_value = ndarray_mask_creation_df[[i, 1]];
-
-                    let point = cut.is_inside(x_value, y_value);
-                    mask.push(point);
-                }
-
-                masks.push(mask);
+    // Keeps the events inside any of the cuts. Lazy, so the cut columns can be computed ones (e.g. Xavg)
+    // as long as they exist in lf. `column` gives the values of a cut column, `col` or e.g. in calibrated units.
+    // The result has every column of lf and the events keep their order.
+    pub fn filter_lf_with_cuts(&self, lf: &LazyFrame, sentinel: f64, column: &dyn Fn(&str) -> Expr) -> LazyFrame {
+        // The mask is a column rather than the filter predicate, polars can't push a predicate with a
+        // custom function down to the file scans
+        lf.clone()
+            .with_column(self.cut_mask(sentinel, column).alias(CUT_MASK_COLUMN))
+            .filter(col(CUT_MASK_COLUMN))
+            .drop([CUT_MASK_COLUMN])
+    }
+}
 
-            }
+// Point in polygon mask of one cut, the polygon is built once for the whole column
+fn polygon_mask(cut: &EditableEguiPolygon, x: Expr, y: Expr, sentinel: f64) -> Expr {
+    let polygon = cut.to_geo_polygon();
+    map_multiple(
+        move |columns: &mut [Series]| {
+            let x_values = columns[0].f64()?;
+            let y_values = columns[1].f64()?;
+            let mask: BooleanChunked = x_values.into_iter().zip(y_values)
+                .map(|(x, y)| match (x, y) {
+                    (Some(x), Some(y)) if x != sentinel && y != sentinel => polygon.contains(&Point::new(x, y)),
+                    _ => false,
+                })
+                .collect();
+            Ok(Some(mask.into_series()))
+        },
+        [x.cast(DataType::Float64), y.cast(DataType::Float64)],
+        GetOutput::from_type(DataType::Boolean),
+    )
+}
 
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Initialize the final combined mask with false values
-        // Assume all masks are of equal length, and `dataset_len` is the length of your dataset
-        let dataset_len = masks.first().map_or(0, |m| m.len());
-        let mut combined_mask = vec![false; dataset_len];
+    // Cut on X and Y with the given corners
+    fn rectangle_cut(x: (f64, f64), y: (f64, f64)) -> CutHandler {
+        let mut cut = EditableEguiPolygon::new();
+        cut.vertices = vec![[x.0, y.0], [x.1, y.0], [x.1, y.1], [x.0, y.1]];
+        cut.selected_x_column = Some("X".to_string());
+        cut.selected_y_column = Some("Y".to_string());
 
-        // Iterate through each mask and combine it with the combined_mask using logical OR
-        for mask in masks {
-            for (i, &value) in mask.iter().enumerate() {
-                combined_mask[i] = combined_mask[i] || value;
-            }
-        }
+        let mut cutter = CutHandler::new();
+        cutter.cuts.insert("cut_1".to_string(), cut);
+        cutter
+    }
 
-        // Convert the combined_mask Vec<bool> to BooleanChunked for filtering
-        let mut boolean_chunked_builder = BooleanChunkedBuilder::new("combined_mask", combined_mask.len());
-        for &value in &combined_mask {
-            boolean_chunked_builder.append_value(value);
-        }
-        let boolean_chunked_series = boolean_chunked_builder.finish();
-        
-        // collect the filtered lazy frame
-        let filtered_df = filtered_lf.collect()?;
+    #[test]
+    fn custom_sentinel_keeps_values_near_default_sentinel() {
+        let cutter = rectangle_cut((-2e6, 100.0), (-2000.0, 2000.0));
+        let df = df!(
+            "X" => [-1e6, -999.0, -500.0, -500.0],
+            "Y" => [0.0, 0.0, 0.0, -999.0],
+        ).unwrap();
 
-        // filter filtered_df with the combined_mask and convert to lazy frame
-        let cuts_filtered_lf = filtered_df.filter(&boolean_chunked_series)?.lazy();
+        let filtered = cutter.filter_lf_with_cuts(&df.lazy(), -999.0, &col).collect().unwrap();
+        let x: Vec<f64> = filtered.column("X").unwrap().f64().unwrap().into_no_null_iter().collect();
 
-        Ok(cuts_filtered_lf)
+        // -1e6 is a value with this sentinel, the events with -999 in either column have no value
+        assert_eq!(x, vec![-1e6, -500.0]);
+        assert_eq!(filtered.column("Y").unwrap().f64().unwrap().get(1), Some(0.0));
     }
 
+    #[test]
+    fn saved_file_keeps_schema_and_event_order() {
+        let directory = std::env::temp_dir().join(format!("cut_save_test_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let input_path = directory.join("run.parquet");
+        let output_path = directory.join("run_cut.parquet");
+
+        let mut df = df!(
+            "X" => [5.0, -50.0, 1.0, 30.0, 2.0, -1e6],
+            "Y" => [0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            "Event" => [0u32, 1, 2, 3, 4, 5],
+            "Label" => ["a", "b", "c", "d", "e", "f"],
+        ).unwrap();
+        ParquetWriter::new(File::create(&input_path).unwrap()).finish(&mut df).unwrap();
+
+        let mut cutter = rectangle_cut((0.0, 10.0), (-1.0, 1.0));
+        let result = cutter.filter_files_and_save_to_one_file(Arc::from(vec![input_path]), &output_path, ScanArgsParquet::default(), -1e6);
+        let saved = result.and_then(|_| LazyFrame::scan_parquet(&output_path, ScanArgsParquet::default())?.collect());
+        std::fs::remove_dir_all(&directory).unwrap();
+        let saved = saved.unwrap();
+
+        assert_eq!(saved.schema(), df.schema());
+        let events: Vec<u32> = saved.column("Event").unwrap().u32().unwrap().into_no_null_iter().collect();
+        assert_eq!(events, vec![0, 2, 4]);
+    }
 }
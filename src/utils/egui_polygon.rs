@@ -11,7 +11,7 @@ use serde_json;
 
 use rfd::FileDialog;
 
-use geo::{Point, Polygon, LineString, algorithm::contains::Contains};
+use geo::{Polygon, LineString};
 
 // typical cut names for sps experiments
 const CUT_COLUMN_NAMES: &[&str] = &[
@@ -19,12 +19,41 @@ const CUT_COLUMN_NAMES: &[&str] = &[
      "ScintLeftEnergy", "Xavg", "X1", "X2"
 ];
 
-#[derive(Serialize, Deserialize, Default)]
+// Colors given to new cuts in turn, so cuts shown together can be told apart
+pub const CUT_COLORS: &[[u8; 3]] = &[
+    [255, 0, 0],
+    [0, 200, 255],
+    [255, 170, 0],
+    [200, 0, 255],
+    [0, 220, 0],
+    [255, 255, 0],
+];
+
+fn default_cut_color() -> [u8; 3] {
+    CUT_COLORS[0]
+}
+
+fn default_stroke_width() -> f32 {
+    4.0
+}
+
+// Cuts saved before the color and stroke width were added are drawn red with a width of 4
+#[derive(Clone, Serialize, Deserialize)]
 pub struct EditableEguiPolygon {
     pub vertices: Vec<[f64; 2]>,        // List of vertex coordinates
     selected_vertex_index: Option<usize>,  // Index of the selected vertex (if any)
     pub selected_x_column: Option<String>,
     pub selected_y_column: Option<String>,
+    #[serde(default = "default_cut_color")]
+    pub color: [u8; 3], // sRGB
+    #[serde(default = "default_stroke_width")]
+    pub stroke_width: f32,
+}
+
+impl Default for EditableEguiPolygon {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl EditableEguiPolygon {
@@ -39,6 +68,16 @@ impl EditableEguiPolygon {
             selected_vertex_index: None,  // Initially, no vertex is selected
             selected_x_column: None,
             selected_y_column: None,
+            color: default_cut_color(),
+            stroke_width: default_stroke_width(),
+        }
+    }
+
+    // New cut drawn in the given color
+    pub fn with_color(color: [u8; 3]) -> Self {
+        Self {
+            color,
+            ..Self::new()
         }
     }
 
@@ -102,14 +141,26 @@ impl EditableEguiPolygon {
         self.clear_selection(); // Clear the selection
     }
 
-    fn draw_vertices_and_polygon(&mut self, plot_ui: &mut PlotUi) {
+    // Dimmed outline without the vertices, for cuts shown next to the one being edited.
+    pub fn draw_outline(&self, plot_ui: &mut PlotUi) {
+        if !self.vertices.is_empty() {
+            let [r, g, b] = self.color;
+            let color = Color32::from_rgb(r, g, b).linear_multiply(0.5);
+            let plot_points = PlotPoints::new(self.vertices.clone());
+            plot_ui.polygon(EguiPolygon::new(plot_points).fill_color(Color32::TRANSPARENT).stroke(Stroke::new(self.stroke_width * 0.5, color)));
+        }
+    }
+
+    // Draws the cut without handling clicks, so it can't be edited
+    pub fn draw_vertices_and_polygon(&self, plot_ui: &mut PlotUi) {
         if !self.vertices.is_empty() {
-            let color = Color32::RED;
+            let [r, g, b] = self.color;
+            let color = Color32::from_rgb(r, g, b);
             let plot_points = PlotPoints::new(self.vertices.clone());
-            let polygon_points = EguiPolygon::new(plot_points).fill_color(Color32::TRANSPARENT).stroke(Stroke::new(4.0, color));
+            let polygon_points = EguiPolygon::new(plot_points).fill_color(Color32::TRANSPARENT).stroke(Stroke::new(self.stroke_width, color));
             plot_ui.polygon(polygon_points); // Draw the polygon
 
-            let vertices = Points::new(self.vertices.clone()).radius(5.0).color(color);
+            let vertices = Points::new(self.vertices.clone()).radius(self.stroke_width + 1.0).color(color);
             plot_ui.points(vertices); // Draw the vertices
         }
     }
@@ -149,7 +200,7 @@ impl EditableEguiPolygon {
         Ok(())
     }
 
-    fn to_geo_polygon(&self) -> Polygon<f64> {
+    pub fn to_geo_polygon(&self) -> Polygon<f64> {
         let exterior_coords: Vec<_> = self.vertices.iter()
             .map(|&[x, y]| (x, y))
             .collect();
@@ -157,10 +208,42 @@ impl EditableEguiPolygon {
         Polygon::new(exterior_line_string, vec![])
     }
 
-    pub fn is_inside(&self, x: f64, y: f64) -> bool {
-        let point = Point::new(x, y);
-        let polygon = self.to_geo_polygon();
-        polygon.contains(&point)
+    // True if the polygon is an axis aligned rectangle, in which case its bounding box is exact.
+    fn is_rectangle(&self) -> bool {
+        let mut vertices = self.vertices.clone();
+        if vertices.len() == 5 && vertices.first() == vertices.last() {
+            vertices.pop(); // closing vertex
+        }
+
+        vertices.len() == 4 && (0..4).all(|i| {
+            let (a, b) = (vertices[i], vertices[(i + 1) % 4]);
+            a[0] == b[0] || a[1] == b[1]
+        })
+    }
+
+    // Polars expression equivalent to the cut for use in scripts. Exact for rectangles,
+    // polygons are approximated by their bounding box.
+    pub fn to_polars_expression(&self) -> Option<String> {
+        let (x_column, y_column) = (self.selected_x_column.as_ref()?, self.selected_y_column.as_ref()?);
+        if self.vertices.len() < 3 {
+            return None;
+        }
+
+        let x_min = self.vertices.iter().map(|v| v[0]).fold(f64::INFINITY, f64::min);
+        let x_max = self.vertices.iter().map(|v| v[0]).fold(f64::NEG_INFINITY, f64::max);
+        let y_min = self.vertices.iter().map(|v| v[1]).fold(f64::INFINITY, f64::min);
+        let y_max = self.vertices.iter().map(|v| v[1]).fold(f64::NEG_INFINITY, f64::max);
+
+        let description = if self.is_rectangle() {
+            format!("// Rectangular cut on {} vs {} (exact)", y_column, x_column)
+        } else {
+            format!("// Bounding box of the {}-vertex polygon cut on {} vs {} (approximate)", self.vertices.len(), y_column, x_column)
+        };
+
+        Some(format!(
+            "{}\ncol(\"{x}\").gt(lit({:?})).and(col(\"{x}\").lt(lit({:?})))\n    .and(col(\"{y}\").gt(lit({:?}))).and(col(\"{y}\").lt(lit({:?})))",
+            description, x_min, x_max, y_min, y_max, x = x_column, y = y_column
+        ))
     }
 
     pub fn cut_ui(&mut self, ui: &mut egui::Ui) {
@@ -194,6 +277,11 @@ impl EditableEguiPolygon {
 
             ui.separator();
 
+            ui.color_edit_button_srgb(&mut self.color).on_hover_text("Color of the cut");
+            ui.add(egui::DragValue::new(&mut self.stroke_width).speed(0.1).clamp_range(0.5..=10.0).prefix("Width: "));
+
+            ui.separator();
+
             // Load Cut button
             if ui.button("Load Cut").clicked() {
                 if let Err(e) = self.load_cut_from_json() {
@@ -209,6 +297,16 @@ impl EditableEguiPolygon {
                 }
             }
 
+            // Copy the cut as a polars filter expression
+            let expression = self.to_polars_expression();
+            if ui.add_enabled(expression.is_some(), egui::Button::new("Copy Expression"))
+                .on_hover_text("Copy a polars filter expression for this cut to the clipboard.\nExact for rectangular cuts, the bounding box for other polygons.\nUse Save Cut for the exact vertices.")
+                .clicked() {
+                if let Some(expression) = expression {
+                    ui.output_mut(|o| o.copied_text = expression);
+                }
+            }
+
             ui.separator();
 
         });
@@ -1,8 +1,67 @@
 
+// Centroid and width of a peak from a Gaussian fit of the bins around it
+#[derive(Clone, Copy)]
+pub struct PeakFit {
+    pub counts: u64, // in the fitted bins
+    pub centroid: f64,
+    pub sigma: f64,
+    pub centroid_error: f64, // statistical uncertainty of the centroid, sigma / sqrt(counts)
+}
+
+// How a 1D histogram is drawn. The bins themselves are not changed.
+#[derive(Clone, Copy, PartialEq)]
+pub struct HistogramView {
+    pub log_y: bool,
+    pub rebin: usize, // bins merged into one
+    pub normalize: bool, // divide by the total counts, to compare runs with different statistics
+}
+
+impl Default for HistogramView {
+    fn default() -> Self {
+        HistogramView { log_y: false, rebin: 1, normalize: false }
+    }
+}
+
+impl HistogramView {
+    // Drawn height of a bin. On a log scale empty bins sit a decade below a single count.
+    pub fn height(&self, count: f64, total_counts: u64) -> f64 {
+        let scale = if self.normalize && total_counts > 0 { 1.0 / total_counts as f64 } else { 1.0 };
+        if self.log_y {
+            (count * scale).max(0.1 * scale).log10()
+        } else {
+            count * scale
+        }
+    }
+
+    // Counts (a fraction of the total when normalized) at a drawn height, the inverse of height
+    pub fn value(&self, height: f64) -> f64 {
+        if self.log_y {
+            10f64.powf(height)
+        } else {
+            height
+        }
+    }
+
+    // Text of the counts at a drawn height, for the tooltips and the crosshair
+    pub fn value_text(&self, height: f64) -> String {
+        let value = self.value(height);
+        if self.normalize && value != 0.0 && value.abs() < 0.01 {
+            format!("{:.3e}", value)
+        } else if self.normalize {
+            format!("{:.4}", value)
+        } else {
+            format!("{:.1}", value)
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Histogram {
-    pub bins: Vec<u32>,
+    pub bins: Vec<u64>, // u64 so a bin can pass 4.29e9 counts without wrapping
     pub range: (f64, f64),
     pub bin_width: f64,
+    pub underflow: u64, // values below the range
+    pub overflow: u64, // values at or above the end of the range
 }
 
 impl Histogram {
@@ -10,8 +69,10 @@ impl Histogram {
     pub fn new(number_of_bins: usize, range: (f64, f64)) -> Self {
         Histogram {
             bins: vec![0; number_of_bins],
-            range : range,
+            range,
             bin_width: (range.1 - range.0) / number_of_bins as f64,
+            underflow: 0,
+            overflow: 0,
         }
     }
 
@@ -22,6 +83,10 @@ impl Histogram {
             if index < self.bins.len() {
                 self.bins[index] += 1;
             }
+        } else if value < self.range.0 {
+            self.underflow += 1;
+        } else if value >= self.range.1 {
+            self.overflow += 1;
         }
     }
 
@@ -31,12 +96,126 @@ impl Histogram {
             return None;
         }
         
-        let bin_index: usize = (((x - self.range.0)) / self.bin_width).floor() as usize;
+        let bin_index: usize = ((x - self.range.0) / self.bin_width).floor() as usize;
         
         Some(bin_index)
     }
 
-    pub fn stats(&self, start_x: f64, end_x: f64) -> (u32, f64, f64) {
+    // Sum of all the bins
+    pub fn total_counts(&self) -> u64 {
+        self.bins.iter().sum()
+    }
+
+    // Get the index and count of the bin with the most counts.
+    pub fn max_bin(&self) -> Option<(usize, u64)> {
+        self.bins.iter()
+            .enumerate()
+            .max_by_key(|&(_, &count)| count)
+            .filter(|&(_, &count)| count > 0)
+            .map(|(index, &count)| (index, count))
+    }
+
+    // Get the center of a bin.
+    pub fn bin_center(&self, bin: usize) -> f64 {
+        self.range.0 + (bin as f64 * self.bin_width) + self.bin_width * 0.5
+    }
+
+    // Full width at half maximum of the highest peak, interpolated between the bins where the counts
+    // cross half of the maximum. None if the peak doesn't fall below half maximum on both sides.
+    pub fn fwhm(&self) -> Option<f64> {
+        let (peak, count) = self.max_bin()?;
+        let half_max = count as f64 / 2.0;
+
+        let left = (0..peak).rev().find(|&bin| (self.bins[bin] as f64) < half_max)?;
+        let right = (peak + 1..self.bins.len()).find(|&bin| (self.bins[bin] as f64) < half_max)?;
+
+        // x where the counts cross half maximum going from bin `above` to its neighbour `below`
+        let crossing = |above: usize, below: usize| {
+            let (above_count, below_count) = (self.bins[above] as f64, self.bins[below] as f64);
+            let fraction = (above_count - half_max) / (above_count - below_count);
+            self.bin_center(above) + fraction * (self.bin_center(below) - self.bin_center(above))
+        };
+
+        Some(crossing(right - 1, right) - crossing(left + 1, left))
+    }
+
+    // Fits a Gaussian to the highest peak, using the bins within one FWHM of its maximum (about 98% of the
+    // counts of a Gaussian peak). The log of the counts is fitted with a parabola, weighted by the counts
+    // squared so the bins in the tails don't dominate (Guo's method). None if there is no peak or the bins
+    // don't curve down like a peak.
+    pub fn fit_main_peak(&self) -> Option<PeakFit> {
+        let (peak, _) = self.max_bin()?;
+        let fwhm = self.fwhm()?;
+        let center = self.bin_center(peak);
+
+        // x relative to the maximum, so the sums stay well conditioned
+        let points: Vec<(f64, f64)> = (0..self.bins.len())
+            .map(|bin| (self.bin_center(bin) - center, self.bins[bin] as f64))
+            .filter(|&(x, count)| x.abs() <= fwhm && count > 0.0)
+            .collect();
+        if points.len() < 3 {
+            return None;
+        }
+
+        // Normal equations of ln(count) = a + b x + c x^2
+        let mut matrix = [[0.0; 3]; 3];
+        let mut rhs = [0.0; 3];
+        for &(x, count) in &points {
+            let weight = count * count;
+            let powers = [1.0, x, x * x];
+            for row in 0..3 {
+                for column in 0..3 {
+                    matrix[row][column] += weight * powers[row] * powers[column];
+                }
+                rhs[row] += weight * count.ln() * powers[row];
+            }
+        }
+        let [_, b, c] = solve_3x3(matrix, rhs)?;
+        if c >= 0.0 {
+            return None;
+        }
+
+        let counts = points.iter().map(|&(_, count)| count).sum::<f64>() as u64;
+        let sigma = (-1.0 / (2.0 * c)).sqrt();
+
+        Some(PeakFit {
+            counts,
+            centroid: center - b / (2.0 * c),
+            sigma,
+            centroid_error: sigma / (counts as f64).sqrt(),
+        })
+    }
+
+    // q-quantile (0 to 1) of the counts between start_x and end_x, interpolated linearly inside the bin
+    // where the cumulative count reaches q of the total. NaN if the range is empty.
+    pub fn quantile_in_range_x(&self, q: f64, start_x: f64, end_x: f64) -> f64 {
+        let start_bin = self.get_bin(start_x).unwrap_or(0);
+        let end_bin = self.get_bin(end_x).unwrap_or(self.bins.len() - 1).min(self.bins.len() - 1);
+        if start_bin > end_bin {
+            return f64::NAN;
+        }
+
+        let total: u64 = self.bins[start_bin..=end_bin].iter().sum();
+        if total == 0 {
+            return f64::NAN;
+        }
+
+        let target = q.clamp(0.0, 1.0) * total as f64;
+        let mut cumulative = 0.0;
+
+        for bin in start_bin..=end_bin {
+            let count = self.bins[bin] as f64;
+            if count > 0.0 && cumulative + count >= target {
+                let fraction = (target - cumulative) / count;
+                return self.range.0 + (bin as f64 + fraction) * self.bin_width;
+            }
+            cumulative += count;
+        }
+
+        self.range.0 + (end_bin + 1) as f64 * self.bin_width
+    }
+
+    pub fn stats(&self, start_x: f64, end_x: f64) -> (u64, f64, f64) {
         let start_bin = self.get_bin(start_x).unwrap_or(0);
         let end_bin = self.get_bin(end_x).unwrap_or(self.bins.len() - 1);
 
@@ -93,14 +272,199 @@ impl Histogram {
         line_points
     }
 
+    // Step line points with every `group` neighbouring bins merged into one step at the height of the
+    // largest of them, so narrow peaks stay visible when there are more bins than pixels. The bins
+    // themselves are not changed.
+    pub fn downsampled_step_points(&self, group: usize) -> Vec<(f64, f64)> {
+        if group <= 1 {
+            return self.step_histogram_points();
+        }
+
+        self.bins.chunks(group)
+            .enumerate()
+            .flat_map(|(index, counts)| {
+                let start = self.range.0 + (index * group) as f64 * self.bin_width;
+                let end = start + counts.len() as f64 * self.bin_width;
+                let max = counts.iter().copied().max().unwrap_or(0) as f64;
+                [(start, max), (end, max)]
+            })
+            .collect()
+    }
+
+    // Copy with every `factor` neighbouring bins summed into one. A partial last group is kept as a
+    // wider range so no counts are lost.
+    pub fn rebinned(&self, factor: usize) -> Histogram {
+        let factor = factor.max(1);
+        let bins: Vec<u64> = self.bins.chunks(factor).map(|counts| counts.iter().sum()).collect();
+        let bin_width = self.bin_width * factor as f64;
+
+        Histogram {
+            range: (self.range.0, self.range.0 + bins.len() as f64 * bin_width),
+            bins,
+            bin_width,
+            underflow: self.underflow,
+            overflow: self.overflow,
+        }
+    }
+
+    // Bin centers, ratios and uncertainties of this histogram over denominator, bin by bin, for an efficiency
+    // where this histogram holds a subset of the events of the denominator. The uncertainty is binomial,
+    // sqrt(e (1 - e) / n), since the counts aren't independent. Bins empty in the denominator are left out.
+    // None if the binning differs.
+    pub fn efficiency(&self, denominator: &Histogram) -> Option<Vec<(f64, f64, f64)>> {
+        if self.bins.len() != denominator.bins.len() || self.range != denominator.range {
+            return None;
+        }
+
+        Some(self.bins.iter()
+            .zip(&denominator.bins)
+            .enumerate()
+            .filter(|&(_, (_, &total))| total > 0)
+            .map(|(index, (&passed, &total))| {
+                let ratio = passed as f64 / total as f64;
+                (self.bin_center(index), ratio, (ratio * (1.0 - ratio) / total as f64).max(0.0).sqrt())
+            })
+            .collect())
+    }
+
+    // Bin centers and counts as CSV.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("bin_center,counts\n");
+        for (index, count) in self.bins.iter().enumerate() {
+            csv.push_str(&format!("{},{}\n", self.bin_center(index), count));
+        }
+        csv
+    }
+
+    // Step points as drawn with a view. group is the number of bins per step before rebinning (see bins_per_pixel).
+    pub fn view_step_points(&self, view: &HistogramView, group: usize) -> Vec<(f64, f64)> {
+        let total_counts = self.total_counts();
+        self.rebinned(view.rebin)
+            .downsampled_step_points((group / view.rebin.max(1)).max(1))
+            .into_iter()
+            .map(|(x, count)| (x, view.height(count, total_counts)))
+            .collect()
+    }
+
+    // Bin centers, drawn heights and the height of an empty bin (the base of the bars) with a view.
+    pub fn view_bars(&self, view: &HistogramView) -> (Vec<(f64, f64)>, f64, f64) {
+        let total_counts = self.total_counts();
+        let rebinned = self.rebinned(view.rebin);
+        let base = view.height(0.0, total_counts);
+        let bars = rebinned.bins.iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(index, &count)| (rebinned.bin_center(index), view.height(count as f64, total_counts)))
+            .collect();
+        (bars, base, rebinned.bin_width)
+    }
+
+    // Number of bins to merge per step so that there is about one step per pixel of the plot.
+    pub fn bins_per_pixel(&self, view_width: f64, pixels: f64) -> usize {
+        if pixels <= 0.0 {
+            return 1;
+        }
+
+        ((view_width / self.bin_width) / pixels).floor().max(1.0) as usize
+    }
+
     /// Generates legend entries for the histogram based on the specified x range.
     pub fn legend_entries(&self, start_x: f64, end_x: f64) -> Vec<String> {
         let stats = self.stats(start_x, end_x);
-        let integral_text = format!("Integral: {}", stats.0);
+        let total = self.total_counts();
+        let percent_of_total = if total > 0 { 100.0 * stats.0 as f64 / total as f64 } else { 0.0 };
+        let integral_text = format!("Integral: {} ({:.2}% of total)", stats.0, percent_of_total);
         let mean_text = format!("Mean: {:.2}", stats.1);
         let stdev_text = format!("Stdev: {:.2}", stats.2);
 
-        vec![integral_text, mean_text, stdev_text]
+        let mut entries = vec![integral_text, mean_text, stdev_text];
+
+        // Less sensitive to the tails than the mean and stdev
+        if stats.0 > 0 {
+            let median = self.quantile_in_range_x(0.5, start_x, end_x);
+            let iqr = self.quantile_in_range_x(0.75, start_x, end_x) - self.quantile_in_range_x(0.25, start_x, end_x);
+            entries.push(format!("Median: {:.2}", median));
+            entries.push(format!("IQR: {:.2}", iqr));
+        }
+
+        // Only shown when there is something outside the range, e.g. with no value events filled
+        if self.underflow > 0 {
+            entries.push(format!("Underflow: {}", self.underflow));
+        }
+        if self.overflow > 0 {
+            entries.push(format!("Overflow: {}", self.overflow));
+        }
+
+        entries
+    }
+
+    // Statistics of the whole histogram for the info window, as (label, value) pairs.
+    pub fn info_entries(&self) -> Vec<(String, String)> {
+        let (counts, mean, stdev) = self.stats(self.range.0, self.range.1);
+
+        let max_bin = match self.max_bin() {
+            Some((bin, count)) => format!("{} counts at {:.2}", count, self.bin_center(bin)),
+            None => "-".to_string(),
+        };
+
+        let fwhm = match self.fwhm() {
+            Some(fwhm) => format!("{:.2}", fwhm),
+            None => "-".to_string(),
+        };
+
+        vec![
+            ("Range".to_string(), format!("{} to {}", self.range.0, self.range.1)),
+            ("Bins".to_string(), format!("{} (width {:.4})", self.bins.len(), self.bin_width)),
+            ("Total Counts".to_string(), counts.to_string()),
+            ("Mean".to_string(), format!("{:.2}", mean)),
+            ("Stdev".to_string(), format!("{:.2}", stdev)),
+            ("Median".to_string(), format!("{:.2}", self.quantile_in_range_x(0.5, self.range.0, self.range.1))),
+            ("IQR".to_string(), format!("{:.2}", self.quantile_in_range_x(0.75, self.range.0, self.range.1) - self.quantile_in_range_x(0.25, self.range.0, self.range.1))),
+            ("Max Bin".to_string(), max_bin),
+            ("FWHM".to_string(), fwhm),
+            ("Underflow".to_string(), self.underflow.to_string()),
+            ("Overflow".to_string(), self.overflow.to_string()),
+        ]
     }
 
 }
+
+// Solves matrix * x = rhs with Cramer's rule, None if the matrix is singular
+fn solve_3x3(matrix: [[f64; 3]; 3], rhs: [f64; 3]) -> Option<[f64; 3]> {
+    let determinant = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+
+    let full = determinant(matrix);
+    if full == 0.0 || !full.is_finite() {
+        return None;
+    }
+
+    let mut solution = [0.0; 3];
+    for (column, value) in solution.iter_mut().enumerate() {
+        let mut replaced = matrix;
+        for row in 0..3 {
+            replaced[row][column] = rhs[row];
+        }
+        *value = determinant(replaced) / full;
+    }
+    Some(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bin_counts_past_u32_max() {
+        let mut hist = Histogram::new(10, (0.0, 10.0));
+        hist.bins[3] = u32::MAX as u64;
+        hist.fill(3.5);
+
+        assert_eq!(hist.bins[3], u32::MAX as u64 + 1);
+        assert_eq!(hist.max_bin(), Some((3, u32::MAX as u64 + 1)));
+        assert_eq!(hist.total_counts(), u32::MAX as u64 + 1);
+    }
+}
@@ -1,4 +1,8 @@
 use fnv::FnvHashMap;
+use geo::{Point, algorithm::contains::Contains};
+use serde::{Serialize, Deserialize};
+
+use crate::utils::egui_polygon::EditableEguiPolygon;
 
 // Define the BarData struct
 pub struct BarData {
@@ -6,31 +10,88 @@ pub struct BarData {
     pub y: f64,
     pub bar_width: f64,
     pub height: f64,
-    pub count: u32,
+    pub count: u64,
+}
+
+// Result of a 2D Gaussian fit
+pub struct Gaussian2DFit {
+    pub counts: u64,
+    pub x_mean: f64,
+    pub y_mean: f64,
+    pub x_sigma: f64,
+    pub y_sigma: f64,
+    pub correlation: f64,
+}
+
+impl Gaussian2DFit {
+    // Points on the 1 sigma ellipse of the fit.
+    pub fn sigma_ellipse(&self, n_points: usize) -> Vec<[f64; 2]> {
+        let root = (1.0 - self.correlation * self.correlation).max(0.0).sqrt();
+        (0..=n_points)
+            .map(|i| {
+                let t = 2.0 * std::f64::consts::PI * i as f64 / n_points as f64;
+                [
+                    self.x_mean + self.x_sigma * t.cos(),
+                    self.y_mean + self.y_sigma * (self.correlation * t.cos() + root * t.sin()),
+                ]
+            })
+            .collect()
+    }
+}
+
+// Auto switches from sparse to dense storage once more than 1/DENSE_FILL_DENOMINATOR of the bins are occupied
+const DENSE_FILL_DENOMINATOR: usize = 4;
+
+// How the bin contents are stored
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum StorageMode {
+    #[default]
+    Auto,   // start sparse, switch to dense as the histogram fills up
+    Sparse,
+    Dense,
+}
+
+// A hash map has zero overhead for empty bins, a flat vector is faster to fill and iterate when most bins are filled
+// Counts are u64, PID plots of multi-billion event runs can overflow u32 in a single bin
+enum Bins {
+    Sparse(FnvHashMap<(usize, usize), u64>),
+    Dense(Vec<u64>), // index = y_index * x_bins + x_index
 }
 
-// uses a hash map to store the histogram data (zero overhead for empty bins)
 pub struct Histogram2D {
-    pub bins: FnvHashMap<(usize, usize), u32>,
+    bins: Bins,
+    storage_mode: StorageMode,
+    pub x_bins: usize,
+    pub y_bins: usize,
     pub x_range: (f64, f64),
     pub y_range: (f64, f64),
     pub x_bin_width: f64,
     pub y_bin_width: f64,
-    pub min_count: u32,
-    pub max_count: u32,
+    pub min_count: u64,
+    pub max_count: u64,
+    pub outside_range: u64, // entries outside the x or y range
 }
 
 impl Histogram2D {
     // Create a new 2D Histogram with specified ranges and number of bins for each axis
-    pub fn new(x_bins: usize, x_range: (f64, f64), y_bins: usize, y_range: (f64, f64)) -> Self {
+    pub fn new(x_bins: usize, x_range: (f64, f64), y_bins: usize, y_range: (f64, f64), storage_mode: StorageMode) -> Self {
+        let bins = match storage_mode {
+            StorageMode::Dense => Bins::Dense(vec![0; x_bins * y_bins]),
+            StorageMode::Auto | StorageMode::Sparse => Bins::Sparse(FnvHashMap::default()),
+        };
+
         Histogram2D {
-            bins: FnvHashMap::default(),
+            bins,
+            storage_mode,
+            x_bins,
+            y_bins,
             x_range,
             y_range,
             x_bin_width: (x_range.1 - x_range.0) / x_bins as f64,
             y_bin_width: (y_range.1 - y_range.0) / y_bins as f64,
-            min_count: u32::MAX,
-            max_count: u32::MIN,
+            min_count: u64::MAX,
+            max_count: u64::MIN,
+            outside_range: 0,
         }
     }
 
@@ -39,28 +100,119 @@ impl Histogram2D {
         if x_value >= self.x_range.0 && x_value < self.x_range.1 && y_value >= self.y_range.0 && y_value < self.y_range.1 {
             let x_index = ((x_value - self.x_range.0) / self.x_bin_width) as usize;
             let y_index = ((y_value - self.y_range.0) / self.y_bin_width) as usize;
-            let count = self.bins.entry((x_index, y_index)).or_insert(0);
-            *count += 1;
+            if x_index >= self.x_bins || y_index >= self.y_bins {
+                return;
+            }
+
+            let count = match &mut self.bins {
+                Bins::Sparse(bins) => {
+                    let count = bins.entry((x_index, y_index)).or_insert(0);
+                    *count += 1;
+                    *count
+                }
+                Bins::Dense(bins) => {
+                    let count = &mut bins[y_index * self.x_bins + x_index];
+                    *count += 1;
+                    *count
+                }
+            };
 
             // Update min and max counts
-            if *count < self.min_count {
-                self.min_count = *count;
+            if count < self.min_count {
+                self.min_count = count;
+            }
+            if count > self.max_count {
+                self.max_count = count;
+            }
+
+            if let Bins::Sparse(bins) = &self.bins {
+                if self.storage_mode == StorageMode::Auto && bins.len() * DENSE_FILL_DENOMINATOR > self.x_bins * self.y_bins {
+                    self.make_dense();
+                }
             }
-            if *count > self.max_count {
-                self.max_count = *count;
+        } else {
+            self.outside_range += 1;
+        }
+    }
+
+    // Moves the bins from the hash map into a flat vector.
+    fn make_dense(&mut self) {
+        if let Bins::Sparse(sparse_bins) = &self.bins {
+            let mut dense_bins = vec![0; self.x_bins * self.y_bins];
+            for (&(x_index, y_index), &count) in sparse_bins {
+                dense_bins[y_index * self.x_bins + x_index] = count;
             }
+            self.bins = Bins::Dense(dense_bins);
+        }
+    }
+
+    // Iterates over the ((x_index, y_index), count) of the non-empty bins.
+    pub fn iter_bins(&self) -> Box<dyn Iterator<Item = ((usize, usize), u64)> + '_> {
+        match &self.bins {
+            Bins::Sparse(bins) => Box::new(bins.iter()
+                .filter(|&(_, &count)| count > 0)
+                .map(|(&index, &count)| (index, count))),
+            Bins::Dense(bins) => Box::new(bins.iter()
+                .enumerate()
+                .filter(|&(_, &count)| count > 0)
+                .map(|(index, &count)| ((index % self.x_bins, index / self.x_bins), count))),
         }
     }
 
+    // Sum of all the bins
+    pub fn total_counts(&self) -> u64 {
+        self.iter_bins().map(|(_, count)| count).sum()
+    }
+
+    // Number of non-empty bins, i.e. the bars the heatmap draws.
+    pub fn occupied_bins(&self) -> usize {
+        match &self.bins {
+            Bins::Sparse(bins) => bins.values().filter(|&&count| count > 0).count(),
+            Bins::Dense(bins) => bins.iter().filter(|&&count| count > 0).count(),
+        }
+    }
+
+    // Copy with factor x factor bins merged into one. The ranges grow to a whole number of merged bins.
+    pub fn rebinned(&self, factor: usize) -> Histogram2D {
+        let factor = factor.max(1);
+        let x_bins = self.x_bins.div_ceil(factor);
+        let y_bins = self.y_bins.div_ceil(factor);
+        let x_range = (self.x_range.0, self.x_range.0 + (x_bins * factor) as f64 * self.x_bin_width);
+        let y_range = (self.y_range.0, self.y_range.0 + (y_bins * factor) as f64 * self.y_bin_width);
+
+        let mut merged: FnvHashMap<(usize, usize), u64> = FnvHashMap::default();
+        for ((x_index, y_index), count) in self.iter_bins() {
+            *merged.entry((x_index / factor, y_index / factor)).or_insert(0) += count;
+        }
+
+        let mut rebinned = Histogram2D::new(x_bins, x_range, y_bins, y_range, StorageMode::Sparse);
+        rebinned.min_count = merged.values().copied().min().unwrap_or(u64::MAX);
+        rebinned.max_count = merged.values().copied().max().unwrap_or(u64::MIN);
+        rebinned.outside_range = self.outside_range;
+        rebinned.bins = Bins::Sparse(merged);
+        rebinned
+    }
+
+    // Smallest merge factor that keeps the heatmap at or under max_bars bars.
+    pub fn rebin_factor_for(&self, max_bars: usize) -> usize {
+        let occupied = self.occupied_bins();
+        if max_bars == 0 || occupied <= max_bars {
+            return 1;
+        }
+
+        // Merging factor x factor bins divides the bars by at most factor^2, so start there
+        let mut factor = ((occupied as f64 / max_bars as f64).sqrt().ceil() as usize).max(2);
+        while factor < self.x_bins.max(self.y_bins) && self.rebinned(factor).occupied_bins() > max_bars {
+            factor += 1;
+        }
+        factor
+    }
+
     // Method to generate data for egui heatmap
     pub fn generate_bar_data(&self) -> Vec<BarData> {
         let mut bars = Vec::new();
 
-        for (&(x_index, y_index), &count) in &self.bins {
-            if count == 0 {
-                continue; // Skip empty bins
-            }
-
+        for ((x_index, y_index), count) in self.iter_bins() {
             let x_bin_start = self.x_range.0 + x_index as f64 * self.x_bin_width;
             let x_bin_end = x_bin_start + self.x_bin_width;
             let y_bin_start = self.y_range.0 + y_index as f64 * self.y_bin_width;
@@ -78,12 +230,25 @@ impl Histogram2D {
         bars
     }
 
+    // Get the (x, y) bin indices and count of the densest cell.
+    pub fn max_bin(&self) -> Option<((usize, usize), u64)> {
+        self.iter_bins().max_by_key(|&(_, count)| count)
+    }
+
+    // Get the center of a cell.
+    pub fn bin_center(&self, x_index: usize, y_index: usize) -> (f64, f64) {
+        (
+            self.x_range.0 + (x_index as f64 * self.x_bin_width) + self.x_bin_width * 0.5,
+            self.y_range.0 + (y_index as f64 * self.y_bin_width) + self.y_bin_width * 0.5,
+        )
+    }
+
     fn get_bin_x(&self, x: f64) -> Option<usize> {
         if x < self.x_range.0 || x > self.x_range.1 {
             return None;
         }
 
-        let bin_index: usize = (((x - self.x_range.0)) / self.x_bin_width).floor() as usize;
+        let bin_index: usize = ((x - self.x_range.0) / self.x_bin_width).floor() as usize;
 
         Some(bin_index)
     }
@@ -93,25 +258,25 @@ impl Histogram2D {
             return None;
         }
 
-        let bin_index: usize = (((y - self.y_range.0)) / self.y_bin_width).floor() as usize;
+        let bin_index: usize = ((y - self.y_range.0) / self.y_bin_width).floor() as usize;
 
         Some(bin_index)
     }
 
-    pub fn stats(&self, start_x: f64, end_x: f64, start_y: f64, end_y: f64) -> (u32, f64, f64, f64, f64) {
+    pub fn stats(&self, start_x: f64, end_x: f64, start_y: f64, end_y: f64) -> (u64, f64, f64, f64, f64) {
 
         let start_x_index = self.get_bin_x(start_x).unwrap_or(0);
-        let end_x_index = self.get_bin_x(end_x).unwrap_or_else(|| self.bins.keys().max_by_key(|k| k.0).map_or(0, |k| k.0));
+        let end_x_index = self.get_bin_x(end_x).unwrap_or_else(|| self.iter_bins().map(|(k, _)| k.0).max().unwrap_or(0));
     
         let start_y_index = self.get_bin_y(start_y).unwrap_or(0);
-        let end_y_index = self.get_bin_y(end_y).unwrap_or_else(|| self.bins.keys().max_by_key(|k| k.1).map_or(0, |k| k.1));
+        let end_y_index = self.get_bin_y(end_y).unwrap_or_else(|| self.iter_bins().map(|(k, _)| k.1).max().unwrap_or(0));
 
         let mut total_count = 0;
 
         let mut sum_product_x = 0.0;
         let mut sum_product_y = 0.0;
 
-        for (&(x_index, y_index), &count) in self.bins.iter() {
+        for ((x_index, y_index), count) in self.iter_bins() {
             if x_index >= start_x_index && x_index <= end_x_index && y_index >= start_y_index && y_index <= end_y_index{ 
 
                 let bin_center_x = self.x_range.0 + (x_index as f64 * self.x_bin_width) + self.x_bin_width * 0.5;
@@ -136,7 +301,7 @@ impl Histogram2D {
             let mut sum_squared_diff_x = 0.0;
             let mut sum_squared_diff_y = 0.0;
     
-            for (&(x_index, y_index), &count) in self.bins.iter() {
+            for ((x_index, y_index), count) in self.iter_bins() {
                 if x_index >= start_x_index && x_index <= end_x_index && y_index >= start_y_index && y_index <= end_y_index{ 
     
                     let bin_center_x = self.x_range.0 + (x_index as f64 * self.x_bin_width) + self.x_bin_width * 0.5;
@@ -158,6 +323,47 @@ impl Histogram2D {
         }
     }
 
+    // Fits a 2D Gaussian to the bins whose centers are inside the cut. The centroid, widths, and correlation
+    // come from the count weighted moments, which is the maximum likelihood estimate for a Gaussian peak.
+    pub fn fit_gaussian_2d(&self, region: &EditableEguiPolygon) -> Option<Gaussian2DFit> {
+        if region.vertices.len() < 3 {
+            return None;
+        }
+        let polygon = region.to_geo_polygon();
+
+        let in_region: Vec<(f64, f64, f64)> = self.iter_bins()
+            .map(|((x_index, y_index), count)| {
+                let (x, y) = self.bin_center(x_index, y_index);
+                (x, y, count as f64)
+            })
+            .filter(|&(x, y, _)| polygon.contains(&Point::new(x, y)))
+            .collect();
+
+        let total: f64 = in_region.iter().map(|&(_, _, count)| count).sum();
+        if total == 0.0 {
+            return None;
+        }
+
+        let x_mean = in_region.iter().map(|&(x, _, count)| count * x).sum::<f64>() / total;
+        let y_mean = in_region.iter().map(|&(_, y, count)| count * y).sum::<f64>() / total;
+
+        let x_variance = in_region.iter().map(|&(x, _, count)| count * (x - x_mean).powi(2)).sum::<f64>() / total;
+        let y_variance = in_region.iter().map(|&(_, y, count)| count * (y - y_mean).powi(2)).sum::<f64>() / total;
+        let covariance = in_region.iter().map(|&(x, y, count)| count * (x - x_mean) * (y - y_mean)).sum::<f64>() / total;
+
+        let (x_sigma, y_sigma) = (x_variance.sqrt(), y_variance.sqrt());
+        let correlation = if x_sigma > 0.0 && y_sigma > 0.0 { covariance / (x_sigma * y_sigma) } else { 0.0 };
+
+        Some(Gaussian2DFit {
+            counts: total as u64,
+            x_mean,
+            y_mean,
+            x_sigma,
+            y_sigma,
+            correlation,
+        })
+    }
+
     /// Generates legend entries for the histogram based on the specified x range.
     pub fn legend_entries(&self, start_x: f64, end_x: f64, start_y: f64, end_y: f64) -> Vec<String> {
         let stats = self.stats(start_x, end_x, start_y, end_y);
@@ -167,7 +373,68 @@ impl Histogram2D {
         let mean_y_text = format!("Mean Y: {:.2}", stats.3);
         let stdev_y_text = format!("Stdev Y: {:.2}", stats.4);
 
-        vec![integral_text, mean_x_text, stdev_x_text, mean_y_text, stdev_y_text]
+        let mut entries = vec![integral_text, mean_x_text, stdev_x_text, mean_y_text, stdev_y_text];
+
+        if self.outside_range > 0 {
+            entries.push(format!("Outside Range: {}", self.outside_range));
+        }
+
+        entries
     }
 
-}
\ No newline at end of file
+    // Statistics of the whole histogram for the info window, as (label, value) pairs.
+    pub fn info_entries(&self) -> Vec<(String, String)> {
+        let (counts, mean_x, stdev_x, mean_y, stdev_y) = self.stats(self.x_range.0, self.x_range.1, self.y_range.0, self.y_range.1);
+
+        let max_bin = match self.max_bin() {
+            Some(((x_index, y_index), count)) => {
+                let (x, y) = self.bin_center(x_index, y_index);
+                format!("{} counts at ({:.2}, {:.2})", count, x, y)
+            }
+            None => "-".to_string(),
+        };
+
+        vec![
+            ("X Range".to_string(), format!("{} to {}", self.x_range.0, self.x_range.1)),
+            ("Y Range".to_string(), format!("{} to {}", self.y_range.0, self.y_range.1)),
+            ("Bins".to_string(), format!("{} x {} (width {:.4} x {:.4})", self.x_bins, self.y_bins, self.x_bin_width, self.y_bin_width)),
+            ("Total Counts".to_string(), counts.to_string()),
+            ("Mean".to_string(), format!("({:.2}, {:.2})", mean_x, mean_y)),
+            ("Stdev".to_string(), format!("({:.2}, {:.2})", stdev_x, stdev_y)),
+            ("Max Bin".to_string(), max_bin),
+            ("Outside Range".to_string(), self.outside_range.to_string()),
+        ]
+    }
+
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::histogrammer::{ColorScale, Histogrammer, HistogramTypes};
+
+    #[test]
+    fn bin_counts_past_u32_max() {
+        let past_u32 = u32::MAX as u64 + 1;
+
+        let mut hist = Histogram2D::new(4, (0.0, 4.0), 4, (0.0, 4.0), StorageMode::Sparse);
+        hist.fill(0.5, 0.5);
+        if let Bins::Sparse(bins) = &mut hist.bins {
+            bins.insert((1, 1), u32::MAX as u64);
+        }
+        hist.fill(1.5, 1.5);
+
+        assert_eq!(hist.iter_bins().find(|&(bin, _)| bin == (1, 1)), Some(((1, 1), past_u32)));
+        assert_eq!((hist.min_count, hist.max_count), (1, past_u32));
+
+        // The colormap spans the full count range
+        assert_eq!(ColorScale::Linear.normalize(past_u32, 1, past_u32), 1.0);
+        assert_eq!(ColorScale::Linear.normalize(1, 1, past_u32), 0.0);
+        assert!((ColorScale::Linear.normalize(past_u32 / 2, 1, past_u32) - 0.5).abs() < 1e-6);
+        assert_eq!(ColorScale::Log.normalize(past_u32, 1, past_u32), 1.0);
+
+        let mut histogrammer = Histogrammer::new();
+        histogrammer.histogram_list.insert("pid".to_string(), HistogramTypes::Hist2D(hist));
+        let heatmap = histogrammer.egui_heatmap("pid", ColorScale::Linear).unwrap();
+        assert_eq!(heatmap.count_range, (1, past_u32));
+    }
+}
@@ -1,11 +1,47 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use eframe::egui::{Color32, Stroke};
 
 use egui_plot::{Bar, Orientation, BarChart, Line, PlotPoints};
 use polars::prelude::*;
+use serde::Serialize;
 
-use crate::utils::histogram1d::Histogram;
-use crate::utils::histogram2d::Histogram2D;
+use crate::utils::histogram1d::{Histogram, HistogramView};
+use crate::utils::histogram2d::{Histogram2D, StorageMode};
+use crate::utils::settings::TimeCalibration;
+
+// A 2D histogram ready to draw
+pub struct Heatmap {
+    pub bar_chart: BarChart,
+    pub rebin_factor: usize, // bins merged to stay under max_heatmap_bars, 1 = drawn as filled
+    pub count_range: (u64, u64), // counts at the ends of the colormap
+}
+
+// How a heatmap is drawn for a max_heatmap_bars, kept between frames since finding the factor and merging
+// the bins goes over every bin. Dropped when the histogram is filled again.
+struct RebinnedHeatmap {
+    max_bars: usize,
+    factor: usize,
+    rebinned: Option<Histogram2D>, // None when drawn as filled
+}
+
+// Part of the setup a histogram belongs to, given by the builder that adds it
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Subsystem {
+    #[default]
+    Sps,
+    Cebra,
+}
+
+impl Subsystem {
+    pub fn label(self) -> &'static str {
+        match self {
+            Subsystem::Sps => "SPS",
+            Subsystem::Cebra => "CeBrA",
+        }
+    }
+}
 
 pub enum HistogramTypes {
     Hist1D(Histogram),
@@ -13,9 +49,103 @@ pub enum HistogramTypes {
 
 }
 
+// Error ending a build once cancel is set. Checked between the steps of a build, a polars collect
+// itself can't be stopped.
+pub fn check_cancel(cancel: &AtomicBool) -> Result<(), PolarsError> {
+    if cancel.load(Ordering::Relaxed) {
+        return Err(PolarsError::ComputeError("histogram build cancelled".into()));
+    }
+    Ok(())
+}
+
+// Number of events filled between progress updates and cancel checks
+const FILL_CHUNK_SIZE: usize = 1_000_000;
+
+// Quantiles that bound an automatic axis range, so a few outliers don't stretch the axis
+const AUTO_RANGE_QUANTILES: (f64, f64) = (0.001, 0.999);
+
+// How far fill_batch has got, reported after every chunk of events
+#[derive(Clone)]
+pub struct FillProgress {
+    pub reading: bool, // the columns are being read from the files, nothing is filled yet
+    pub events_filled: usize,
+    pub total_events: usize,
+    pub histogram_counts: Vec<(String, u64)>, // counts so far of each histogram in the batch
+}
+
+// Raw time columns of the last build, kept when settings.live_recalibration is on so a change to a
+// time calibration refills the affected histograms from memory instead of reading the files again.
+// Built and used by histogram_creation::recalibrate.
+pub struct RecalibrationCache {
+    pub frame: DataFrame, // uncalibrated columns and selections of the time histograms, after the cuts
+    pub ranges: HashMap<String, Vec<(f64, f64)>>, // uncalibrated axis ranges (x first) of each time histogram
+    pub calibrations: Vec<TimeCalibration>, // calibrations last applied
+}
+
+impl RecalibrationCache {
+    // Stops refilling a histogram on calibration changes, returns whether it was refilled until now
+    pub fn forget(&mut self, name: &str) -> bool {
+        self.ranges.remove(name).is_some()
+    }
+}
+
+// Definition of a built histogram, written to the manifest of a run
+#[derive(Serialize)]
+pub struct ManifestEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str, // "1D" or "2D"
+    pub bins: Vec<usize>, // per axis, x first
+    pub ranges: Vec<(f64, f64)>,
+    pub columns: Vec<String>, // empty if the histogram wasn't filled by the Histogrammer
+    pub selection: Option<String>,
+    pub counts: u64,
+}
+
+// Outcome of filling a single histogram. An empty histogram can be a valid result (NoEvents: every event
+// was filtered out or had no value) or a problem (Failed), the load report says which.
+#[derive(Debug)]
+pub enum FillStatus {
+    Filled,
+    NoEvents,
+    NotFound, // no histogram of the right type with that name
+    Failed(String), // reading the columns or converting them failed
+}
+
+impl From<Result<usize, PolarsError>> for FillStatus {
+    fn from(result: Result<usize, PolarsError>) -> Self {
+        match result {
+            Ok(0) => FillStatus::NoEvents,
+            Ok(_) => FillStatus::Filled,
+            Err(e) => FillStatus::Failed(e.to_string()),
+        }
+    }
+}
+
+// A histogram waiting to be filled by fill_batch. The selection is the name of a boolean column,
+// only the events where it is true are filled.
+#[derive(Clone)]
+enum BatchFill {
+    Hist1D { name: String, column: String, selection: Option<String> },
+    Hist2D { name: String, x_column: String, y_column: String, selection: Option<String> },
+}
+
 #[derive(Default)]
 pub struct Histogrammer {
     pub histogram_list: HashMap<String, HistogramTypes>,
+    pub storage_2d: StorageMode,
+    batch: Vec<BatchFill>,
+    sources: HashMap<String, BatchFill>, // columns each histogram was filled from, for refill_with_ranges and the manifest
+    pub load_report: Vec<String>, // problems found while building, shown after loading
+    pub sentinel: f64, // value marking "no value" in a column
+    pub fill_invalid: bool, // fill the sentinel values too, to see how often a column has no value
+    pub per_file_stems: Vec<String>, // files with per file histograms ("{name} [{stem}]"), in load order
+    pub max_heatmap_bars: usize, // heatmaps with more occupied bins are drawn rebinned, 0 = no limit
+    pub sample_fraction: f64, // fraction of the events the histograms were built from, 1 = all
+    pub recalibration: Option<Box<RecalibrationCache>>, // raw time columns kept for histogram_creation::recalibrate
+    subsystem: Subsystem, // given to the histograms added, see with_subsystem
+    subsystems: HashMap<String, Subsystem>, // subsystem of each histogram
+    heatmap_cache: RefCell<HashMap<String, RebinnedHeatmap>>, // see egui_heatmap
 }
 
 impl Histogrammer {
@@ -24,71 +154,126 @@ impl Histogrammer {
     pub fn new() -> Self {
         Self {
             histogram_list: HashMap::new(), 
+            storage_2d: StorageMode::Auto,
+            batch: Vec::new(),
+            sources: HashMap::new(),
+            load_report: Vec::new(),
+            sentinel: -1e6,
+            fill_invalid: false,
+            per_file_stems: Vec::new(),
+            max_heatmap_bars: 100_000,
+            sample_fraction: 1.0,
+            recalibration: None,
+            subsystem: Subsystem::Sps,
+            subsystems: HashMap::new(),
+            heatmap_cache: RefCell::new(HashMap::new()),
         }
     }
 
+    // Adding a name twice is almost always a copy-paste mistake in the builder, so it is reported.
+    // With the same binning the new definition replaces the first one and the queued fill of the replaced
+    // histogram is dropped so the events aren't filled twice. With a different binning (or type) the first
+    // histogram is kept and false is returned, so the new definition is not added or filled.
+    fn check_duplicate(&mut self, name: &str, same_binning: impl Fn(&HistogramTypes) -> bool) -> bool {
+        let Some(existing) = self.histogram_list.get(name) else {
+            return true;
+        };
+
+        let compatible = same_binning(existing);
+        let message = if compatible {
+            format!("Histogram '{}' is defined more than once, only the last definition is kept", name)
+        } else {
+            format!("Histogram '{}' is defined again with a different binning, the first definition is kept", name)
+        };
+        if cfg!(debug_assertions) {
+            eprintln!("{}", message);
+        }
+        self.load_report.push(message);
+
+        if compatible {
+            self.batch.retain(|fill| match fill {
+                BatchFill::Hist1D { name: queued, .. } | BatchFill::Hist2D { name: queued, .. } => queued != name,
+            });
+        }
+
+        compatible
+    }
+
+    // A histogram whose bins have no width or run backwards would fill garbage bin indices, so it is
+    // reported and not added.
+    fn check_binning(&mut self, name: &str, axes: &[(usize, (f64, f64))]) -> bool {
+        for &(bins, range) in axes {
+            let problem = if bins == 0 {
+                Some("has no bins".to_string())
+            } else if !range.0.is_finite() || !range.1.is_finite() {
+                Some(format!("has a range that isn't finite ({}, {})", range.0, range.1))
+            } else if range.1 <= range.0 {
+                Some(format!("has an empty or inverted range ({}, {}), the bin width would be {}", range.0, range.1, (range.1 - range.0) / bins as f64))
+            } else {
+                None
+            };
+
+            if let Some(problem) = problem {
+                let message = format!("Skipped '{}': it {}", name, problem);
+                eprintln!("{}", message);
+                self.load_report.push(message);
+                return false;
+            }
+        }
+        true
+    }
+
     // Adds a new 1D histogram to the histogram list.
-    pub fn add_hist1d(&mut self, name: &str, bins: usize, range: (f64, f64)) {
+    // Returns false if the binning is invalid (see check_binning) or the name is already used by a
+    // histogram with a different binning (see check_duplicate).
+    pub fn add_hist1d(&mut self, name: &str, bins: usize, range: (f64, f64)) -> bool {
+        if !self.check_binning(name, &[(bins, range)]) {
+            return false;
+        }
+
+        let same_binning = |existing: &HistogramTypes| matches!(existing, HistogramTypes::Hist1D(hist) if hist.bins.len() == bins && hist.range == range);
+        if !self.check_duplicate(name, same_binning) {
+            return false;
+        }
+
         let hist: Histogram = Histogram::new(bins, range); // Create a new histogram.
         self.histogram_list.insert(name.to_string(), HistogramTypes::Hist1D(hist)); // Store it in the hashmap.
+        self.subsystems.insert(name.to_string(), self.subsystem);
+        true
     }
 
     // Fills a 1D histogram with data from a polars dataframe/column.
-    pub fn fill_hist1d(&mut self, name: &str, lf: &LazyFrame, column_name: &str) -> bool {
-        let hist: &mut Histogram = match self.histogram_list.get_mut(name) {
-            Some(HistogramTypes::Hist1D(hist)) => hist,
-            _ => return false,  // Return false if the histogram doesn't exist.
-        };
-
-        // Attempt to collect the LazyFrame into a DataFrame
-        let df_result = lf.clone().select([col(column_name)]).collect();
-
-        // Handle the Result before proceeding
-        match df_result {
-            Ok(df) => {
-                // Now that we have a DataFrame, we can attempt to convert it to an ndarray
-                let ndarray_df_result = df.to_ndarray::<Float64Type>(IndexOrder::Fortran);
-
-                match ndarray_df_result {
-                    Ok(ndarray_df) => {
-                        // You now have the ndarray and can proceed with your logic
-                        let shape = ndarray_df.shape();
-                        let rows = shape[0];
-
-                        // Iterating through the ndarray and filling the histogram
-                        for i in 0..rows {
-                            let value = ndarray_df[[i, 0]];
-                            hist.fill(value);
-                        }
-
-                        true
-                    },
-                    Err(e) => {
-                        // Handle the error, for example, log it or return an error
-                        eprintln!("Failed to convert DataFrame to ndarray: {}", e);
-                        false
-                    }
-                }
-            },
-            Err(e) => {
-                // Handle the error, for example, log it or return an error
-                eprintln!("Failed to collect LazyFrame: {}", e);
-                false
-            }
+    // A histogram left empty is explained in the load report, see FillStatus.
+    #[allow(dead_code)] // the builder fills in batches, kept for one off histograms
+    pub fn fill_hist1d(&mut self, name: &str, lf: &LazyFrame, column_name: &str) -> FillStatus {
+        let mut lf = lf.clone().select([col(column_name)]).filter(col(column_name).is_not_null());
+        if !self.fill_invalid {
+            lf = lf.filter(col(column_name).neq(lit(self.sentinel)));
         }
 
+        let status = match self.histogram_list.get_mut(name) {
+            Some(HistogramTypes::Hist1D(hist)) => for_each_event(lf, |event| hist.fill(event[0])).into(),
+            _ => FillStatus::NotFound,
+        };
+
+        self.report_fill_status(name, &status);
+        status
     }
 
     // Adds and fills a 1D histogram with data from a Polars LazyFrame.
+    #[allow(dead_code)]
     pub fn add_fill_hist1d(&mut self, name: &str, lf: &LazyFrame, column_name: &str, bins: usize, range: (f64, f64)) {
-        self.add_hist1d(name, bins, range);  // Add the histogram.
-        self.fill_hist1d(name, lf, column_name);  // Fill it with data.
+        if self.add_hist1d(name, bins, range) {  // Add the histogram.
+            self.fill_hist1d(name, lf, column_name);  // Fill it with data.
+            self.sources.insert(name.to_string(), BatchFill::Hist1D { name: name.to_string(), column: column_name.to_string(), selection: None });
+        }
     }
 
     // Generates a histogram using the bar chart from the `egui` library.
-    pub fn egui_histogram_step(&self, name: &str, color: Color32) -> Option<Line> {
+    // Neighbouring bins are merged in groups of `group` for drawing (see Histogram::downsampled_step_points).
+    pub fn egui_histogram_step(&self, name: &str, color: Color32, group: usize, view: &HistogramView) -> Option<Line> {
         if let Some(HistogramTypes::Hist1D(hist)) = self.histogram_list.get(name) {
-            let line_points = hist.step_histogram_points();
+            let line_points = hist.view_step_points(view, group);
 
             // Convert line_points to a Vec<[f64; 2]>
             let plot_points: PlotPoints = line_points.iter().map(|&(x, y)| [x, y]).collect();
@@ -100,80 +285,487 @@ impl Histogrammer {
         }
     }
     
+    // Draws a 1D histogram as filled bars, one per non-empty bin. The fill has the given opacity (0 to 1)
+    // so overlaid histograms can show through each other, the outline is always opaque.
+    pub fn egui_histogram_bars(&self, name: &str, color: Color32, view: &HistogramView, fill_opacity: f32) -> Option<BarChart> {
+        if let Some(HistogramTypes::Hist1D(hist)) = self.histogram_list.get(name) {
+            let (heights, base, bin_width) = hist.view_bars(view);
+            let bars: Vec<Bar> = heights.into_iter()
+                .map(|(center, height)| {
+                    Bar::new(center, height - base)
+                        .base_offset(base)
+                        .width(bin_width)
+                        .fill(color.linear_multiply(fill_opacity))
+                        .stroke(Stroke::new(1.0, color))
+                })
+                .collect();
+
+            // The tooltip shows the counts, not the drawn height (log10 on a log scale)
+            let (view, chart_name) = (*view, name.to_string());
+            let formatter = move |bar: &Bar, _: &BarChart| {
+                format!("{}\nx = {:.2}\n{}", chart_name, bar.argument, view.value_text(bar.base_offset.unwrap_or(0.0) + bar.value))
+            };
+
+            Some(BarChart::new(bars).color(color).name(name).element_formatter(Box::new(formatter)))
+        } else {
+            None
+        }
+    }
+
+    // Counts inside the range of a 1D or 2D histogram.
+    pub fn total_counts(&self, name: &str) -> Option<u64> {
+        match self.histogram_list.get(name)? {
+            HistogramTypes::Hist1D(hist) => Some(hist.total_counts()),
+            HistogramTypes::Hist2D(hist) => Some(hist.total_counts()),
+        }
+    }
+
     // Adds a new 2D histogram to the histogram list.
-    pub fn add_hist2d(&mut self, name: &str, x_bins: usize, x_range: (f64, f64), y_bins: usize, y_range: (f64, f64)) {
-        let hist: Histogram2D = Histogram2D::new(x_bins, x_range, y_bins, y_range); // Create a new 2D histogram.
+    // Returns false if the name is already used by a histogram with a different binning (see check_duplicate).
+    pub fn add_hist2d(&mut self, name: &str, x_bins: usize, x_range: (f64, f64), y_bins: usize, y_range: (f64, f64)) -> bool {
+        if !self.check_binning(name, &[(x_bins, x_range), (y_bins, y_range)]) {
+            return false;
+        }
+
+        let same_binning = |existing: &HistogramTypes| matches!(existing, HistogramTypes::Hist2D(hist)
+            if hist.x_bins == x_bins && hist.x_range == x_range && hist.y_bins == y_bins && hist.y_range == y_range);
+        if !self.check_duplicate(name, same_binning) {
+            return false;
+        }
+
+        let hist: Histogram2D = Histogram2D::new(x_bins, x_range, y_bins, y_range, self.storage_2d); // Create a new 2D histogram.
         self.histogram_list.insert(name.to_string(), HistogramTypes::Hist2D(hist)); // Store it in the hashmap.
+        self.subsystems.insert(name.to_string(), self.subsystem);
+        true
     }
 
     // Fills a 2D histogram with x and y data.
-    pub fn fill_hist2d(&mut self, name: &str, lf: &LazyFrame, x_column_name: &str, y_column_name: &str) -> bool {
-        let hist: &mut Histogram2D = match self.histogram_list.get_mut(name) {
-            Some(HistogramTypes::Hist2D(hist)) => hist,
-            _ => return false, // Return false if the histogram doesn't exist.
+    // A histogram left empty is explained in the load report, see FillStatus.
+    pub fn fill_hist2d(&mut self, name: &str, lf: &LazyFrame, x_column_name: &str, y_column_name: &str) -> FillStatus {
+        let mut lf = lf.clone()
+            .select([col(x_column_name), col(y_column_name)])
+            .filter(col(x_column_name).is_not_null().and(col(y_column_name).is_not_null()));
+        if !self.fill_invalid {
+            lf = lf
+                .filter(col(x_column_name).neq(lit(self.sentinel)))
+                .filter(col(y_column_name).neq(lit(self.sentinel)));
+        }
+
+        let status = match self.histogram_list.get_mut(name) {
+            Some(HistogramTypes::Hist2D(hist)) => for_each_event(lf, |event| hist.fill(event[0], event[1])).into(),
+            _ => FillStatus::NotFound,
         };
 
-        // Attempt to collect the LazyFrame into a DataFrame
-        let df_result = lf.clone()
-            .select([col(x_column_name), col(y_column_name)])
-            .filter(col(x_column_name).neq(lit(-1e6)))
-            .filter(col(y_column_name).neq(lit(-1e6)))
+        self.report_fill_status(name, &status);
+        status
+    }
+
+    // Histograms that end up empty, or couldn't be filled, are listed in the load report, once if they are refilled
+    fn report_fill_status(&mut self, name: &str, status: &FillStatus) {
+        let message = match status {
+            FillStatus::Filled => return,
+            FillStatus::NoEvents => format!("'{}' is empty: no events have a value in its columns", name),
+            FillStatus::NotFound => format!("'{}' could not be filled: no histogram of that type has this name", name),
+            FillStatus::Failed(e) => format!("'{}' could not be filled: {}", name, e),
+        };
+        if !self.load_report.contains(&message) {
+            eprintln!("{}", message);
+            self.load_report.push(message);
+        }
+    }
+
+    // Adds and fills a 2D histogram with data from Polars LazyFrame columns.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_fill_hist2d(&mut self, name: &str, lf: &LazyFrame, x_column_name: &str, x_bins: usize, x_range: (f64, f64), y_column_name: &str, y_bins: usize, y_range: (f64, f64)) {
+        if self.add_hist2d(name, x_bins, x_range, y_bins, y_range) { // Add the histogram.
+            self.fill_hist2d(name, lf, x_column_name, y_column_name); // Fill it with data.
+            self.sources.insert(name.to_string(), BatchFill::Hist2D {
+                name: name.to_string(),
+                x_column: x_column_name.to_string(),
+                y_column: y_column_name.to_string(),
+                selection: None,
+            });
+        }
+    }
+
+    // Range between the low and high quantiles (0 to 1) of a column, without the no value markers.
+    // None if the column has no values.
+    pub fn quantile_range(&self, lf: &LazyFrame, column_name: &str, low: f64, high: f64) -> Result<Option<(f64, f64)>, PolarsError> {
+        let values = col(column_name).cast(DataType::Float64);
+        let df = lf.clone()
+            .filter(col(column_name).neq(lit(self.sentinel)))
+            .select([
+                values.clone().quantile(lit(low), QuantileInterpolOptions::Linear).alias("low"),
+                values.quantile(lit(high), QuantileInterpolOptions::Linear).alias("high"),
+            ])
+            .collect()?;
+
+        match (df.column("low")?.f64()?.get(0), df.column("high")?.f64()?.get(0)) {
+            (Some(low), Some(high)) if high > low => Ok(Some((low, high))),
+            (Some(low), Some(_)) => Ok(Some((low - 0.5, low + 0.5))), // a single value
+            _ => Ok(None),
+        }
+    }
+
+    // Adds and fills a 2D histogram. An axis without a range is framed around its populated region, from
+    // the AUTO_RANGE_QUANTILES of the column, so a pair of unfamiliar columns doesn't end up crammed in a corner.
+    // With snap, those ranges are rounded with nice_range. Failures are listed in the load report.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_fill_hist2d_auto(&mut self, name: &str, lf: &LazyFrame, x_column_name: &str, x_bins: usize, x_range: Option<(f64, f64)>, y_column_name: &str, y_bins: usize, y_range: Option<(f64, f64)>, snap: bool) -> bool {
+        let mut axes = [(x_column_name, x_bins, x_range), (y_column_name, y_bins, y_range)];
+
+        for (column_name, bins, range) in axes.iter_mut() {
+            if range.is_some() {
+                continue;
+            }
+
+            match self.quantile_range(lf, column_name, AUTO_RANGE_QUANTILES.0, AUTO_RANGE_QUANTILES.1) {
+                Ok(Some(quantiles)) if snap => {
+                    let (nice, nice_bins) = nice_range(quantiles, *bins);
+                    *range = Some(nice);
+                    *bins = nice_bins;
+                }
+                Ok(Some(quantiles)) => *range = Some(quantiles),
+                Ok(None) => {
+                    self.load_report.push(format!("'{}' was not added: no values in '{}' to set its range", name, column_name));
+                    return false;
+                }
+                Err(e) => {
+                    self.load_report.push(format!("'{}' was not added: failed to get the range of '{}': {}", name, column_name, e));
+                    return false;
+                }
+            }
+        }
+
+        let [(_, x_bins, Some(x_range)), (_, y_bins, Some(y_range))] = axes else {
+            return false;
+        };
+
+        self.add_fill_hist2d(name, lf, x_column_name, x_bins, x_range, y_column_name, y_bins, y_range);
+        true
+    }
+
+    // Adds a 1D histogram that is filled later, together with the rest of the batch, by fill_batch.
+    pub fn add_batch_hist1d(&mut self, name: &str, column_name: &str, bins: usize, range: (f64, f64), selection: Option<&str>) {
+        if !self.add_hist1d(name, bins, range) {
+            return;
+        }
+        let fill = BatchFill::Hist1D {
+            name: name.to_string(),
+            column: column_name.to_string(),
+            selection: selection.map(str::to_string),
+        };
+        self.sources.insert(name.to_string(), fill.clone());
+        self.batch.push(fill);
+    }
+
+    // Adds a 2D histogram that is filled later, together with the rest of the batch, by fill_batch.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_batch_hist2d(&mut self, name: &str, x_column_name: &str, x_bins: usize, x_range: (f64, f64), y_column_name: &str, y_bins: usize, y_range: (f64, f64), selection: Option<&str>) {
+        if !self.add_hist2d(name, x_bins, x_range, y_bins, y_range) {
+            return;
+        }
+        let fill = BatchFill::Hist2D {
+            name: name.to_string(),
+            x_column: x_column_name.to_string(),
+            y_column: y_column_name.to_string(),
+            selection: selection.map(str::to_string),
+        };
+        self.sources.insert(name.to_string(), fill.clone());
+        self.batch.push(fill);
+    }
+
+    // Drops every histogram, and its queued fill, that isn't in names.
+    pub fn keep_only(&mut self, names: &[String]) {
+        self.histogram_list.retain(|name, _| names.contains(name));
+        self.sources.retain(|name, _| names.contains(name));
+        self.batch.retain(|fill| match fill {
+            BatchFill::Hist1D { name, .. } | BatchFill::Hist2D { name, .. } => names.contains(name),
+        });
+    }
+
+    // Histograms added by add run under subsystem, the ones added after get the previous subsystem again.
+    pub fn with_subsystem<T>(&mut self, subsystem: Subsystem, add: impl FnOnce(&mut Self) -> T) -> T {
+        let previous = std::mem::replace(&mut self.subsystem, subsystem);
+        let result = add(self);
+        self.subsystem = previous;
+        result
+    }
+
+    // Subsystem of a histogram, None if it wasn't added through add_hist1d or add_hist2d.
+    pub fn subsystem_of(&self, name: &str) -> Option<Subsystem> {
+        self.subsystems.get(name).copied()
+    }
+
+    // Gives a histogram the subsystem of another, e.g. for copies under a new name.
+    pub fn copy_subsystem(&mut self, from: &str, to: &str) {
+        if let Some(subsystem) = self.subsystems.get(from).copied() {
+            self.subsystems.insert(to.to_string(), subsystem);
+        }
+    }
+
+    // Columns (x first) and selection a histogram was filled from.
+    pub fn source_columns(&self, name: &str) -> Option<(Vec<&str>, Option<&str>)> {
+        match self.sources.get(name)? {
+            BatchFill::Hist1D { column, selection, .. } => Some((vec![column.as_str()], selection.as_deref())),
+            BatchFill::Hist2D { x_column, y_column, selection, .. } => Some((vec![x_column.as_str(), y_column.as_str()], selection.as_deref())),
+        }
+    }
+
+    // Name, binning and source columns of every histogram, sorted by name.
+    pub fn manifest(&self) -> Vec<ManifestEntry> {
+        let mut entries: Vec<ManifestEntry> = self.histogram_list.iter()
+            .map(|(name, histogram)| {
+                let (kind, bins, ranges) = match histogram {
+                    HistogramTypes::Hist1D(hist) => ("1D", vec![hist.bins.len()], vec![hist.range]),
+                    HistogramTypes::Hist2D(hist) => ("2D", vec![hist.x_bins, hist.y_bins], vec![hist.x_range, hist.y_range]),
+                };
+                // Per file copies ("{name} [{stem}]") were filled from the same columns as the histogram they copy
+                let source = self.sources.get(name).or_else(|| {
+                    self.per_file_stems.iter()
+                        .find_map(|stem| name.strip_suffix(&format!(" [{}]", stem)))
+                        .and_then(|base| self.sources.get(base))
+                });
+                let (columns, selection) = match source {
+                    Some(BatchFill::Hist1D { column, selection, .. }) => (vec![column.clone()], selection.clone()),
+                    Some(BatchFill::Hist2D { x_column, y_column, selection, .. }) => (vec![x_column.clone(), y_column.clone()], selection.clone()),
+                    None => (Vec::new(), None),
+                };
+
+                ManifestEntry {
+                    name: name.clone(),
+                    kind,
+                    bins,
+                    ranges,
+                    columns,
+                    selection,
+                    counts: self.total_counts(name).unwrap_or(0),
+                }
+            })
             .collect();
 
-        // Handle the Result before proceeding
-        match df_result {
-            Ok(df) => {
-                // Now that we have a DataFrame, we can attempt to convert it to an ndarray
-                let ndarray_df_result = df.to_ndarray::<Float64Type>(IndexOrder::Fortran);
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries
+    }
 
-                match ndarray_df_result {
-                    Ok(ndarray_df) => {
-                        // You now have the ndarray and can proceed with your logic
-                        let shape = ndarray_df.shape();
-                        let rows = shape[0];
+    // Puts the histograms refilled with a cut (see histogram_creation::refill_histograms) in place of the ones
+    // with the same names, the others are kept. Histograms in names the refill doesn't have keep their contents.
+    // Refilled histograms leave the recalibration cache, it only holds the events of the last full build.
+    pub fn replace_refilled(&mut self, refilled: Histogrammer, names: &[String]) {
+        let mut replaced = Vec::new();
+        for (name, hist) in refilled.histogram_list {
+            self.heatmap_cache.get_mut().remove(&name);
+            if let Some(cache) = &mut self.recalibration {
+                if cache.forget(&name) {
+                    self.load_report.push(format!("'{}' no longer follows time calibration changes, it was refilled with the cut", name));
+                }
+            }
+            self.histogram_list.insert(name.clone(), hist);
+            replaced.push(name);
+        }
 
-                        // Iterating through the ndarray rows and filling the 2D histogram
-                        for i in 0..rows {
-                            let x_value = ndarray_df[[i, 0]];
-                            let y_value = ndarray_df[[i, 1]];
+        self.load_report.extend(refilled.load_report.into_iter().map(|line| format!("Refill with the cut: {}", line)));
+        for name in names.iter().filter(|name| !replaced.contains(name)) {
+            self.load_report.push(format!("'{}' could not be refilled with the cut, it keeps its previous contents", name));
+        }
+    }
 
-                            hist.fill(x_value, y_value);
-                        }
+    // Empties a histogram, gives it new axis ranges (x first, the bins are kept) and fills it again from the
+    // same columns in lf. Used to follow a calibration change without rebuilding the other histograms.
+    pub fn refill_with_ranges(&mut self, name: &str, ranges: &[(f64, f64)], lf: &LazyFrame) -> Result<(), PolarsError> {
+        let Some(fill) = self.sources.get(name).cloned() else {
+            return Err(PolarsError::ComputeError(format!("histogram '{}' has no source columns to refill from", name).into()));
+        };
+
+        if let Some(range) = ranges.iter().find(|range| !range.0.is_finite() || !range.1.is_finite() || range.1 <= range.0) {
+            return Err(PolarsError::ComputeError(format!("histogram '{}' can't have the range ({}, {})", name, range.0, range.1).into()));
+        }
+
+        let storage_2d = self.storage_2d;
+        match (self.histogram_list.get_mut(name), ranges) {
+            (Some(HistogramTypes::Hist1D(hist)), &[range]) => *hist = Histogram::new(hist.bins.len(), range),
+            (Some(HistogramTypes::Hist2D(hist)), &[x_range, y_range]) => *hist = Histogram2D::new(hist.x_bins, x_range, hist.y_bins, y_range, storage_2d),
+            _ => return Err(PolarsError::ComputeError(format!("histogram '{}' doesn't have {} axes", name, ranges.len()).into())),
+        }
+        self.heatmap_cache.get_mut().remove(name);
+
+        // The pending batch is put back after, so only this histogram is filled
+        let pending = std::mem::replace(&mut self.batch, vec![fill]);
+        let result = self.fill_batch(lf, &|_| {}, &AtomicBool::new(false));
+        self.batch = pending;
+        result
+    }
+
+    // Fills every batched histogram in a single pass over the data.
+    //
+    // All of the columns used by the batch (values and selections) are collected from the LazyFrame at
+    // once, so the files are only read one time instead of once per histogram. Those columns are held in
+    // memory for the whole fill, the chunks don't reduce that: they are only there to call progress after
+    // each one and to check cancel, which stops the fill before the next chunk with an error.
+    pub fn fill_batch(&mut self, lf: &LazyFrame, progress: &dyn Fn(FillProgress), cancel: &AtomicBool) -> Result<(), PolarsError> {
+        let mut batch = std::mem::take(&mut self.batch);
+
+        // Histograms that need a column the files don't have are dropped instead of failing the whole batch
+        let schema = lf.schema()?;
+        batch.retain(|fill| {
+            let (name, columns, selection) = match fill {
+                BatchFill::Hist1D { name, column, selection } => (name, vec![column], selection),
+                BatchFill::Hist2D { name, x_column, y_column, selection } => (name, vec![x_column, y_column], selection),
+            };
+
+            match columns.into_iter().chain(selection).find(|column| !schema.contains(column)) {
+                Some(missing) => {
+                    self.load_report.push(format!("Skipped '{}': column '{}' is not in the files", name, missing));
+                    self.histogram_list.remove(name);
+                    false
+                }
+                None => true,
+            }
+        });
+
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        // The heatmaps are drawn from the new counts
+        for fill in &batch {
+            let (BatchFill::Hist1D { name, .. } | BatchFill::Hist2D { name, .. }) = fill;
+            self.heatmap_cache.get_mut().remove(name);
+        }
+
+        let mut value_columns: Vec<&str> = Vec::new();
+        let mut selection_columns: Vec<&str> = Vec::new();
+        for fill in &batch {
+            let (columns, selection) = match fill {
+                BatchFill::Hist1D { column, selection, .. } => (vec![column.as_str()], selection),
+                BatchFill::Hist2D { x_column, y_column, selection, .. } => (vec![x_column.as_str(), y_column.as_str()], selection),
+            };
+
+            for column in columns {
+                if !value_columns.contains(&column) {
+                    value_columns.push(column);
+                }
+            }
 
-                        true
-                    },
-                    Err(e) => {
-                        // Handle the error, for example, log it or return an error
-                        eprintln!("Failed to convert DataFrame to ndarray: {}", e);
-                        false
+            if let Some(selection) = selection {
+                if !selection_columns.contains(&selection.as_str()) {
+                    selection_columns.push(selection.as_str());
+                }
+            }
+        }
+
+        progress(FillProgress {
+            reading: true,
+            events_filled: 0,
+            total_events: 0,
+            histogram_counts: Vec::new(),
+        });
+
+        check_cancel(cancel)?;
+        let df = lf.clone()
+            .select(value_columns.iter().chain(selection_columns.iter()).map(|&column| col(column)).collect::<Vec<Expr>>())
+            .collect()?;
+
+        let total_events = df.height();
+        for offset in (0..total_events).step_by(FILL_CHUNK_SIZE) {
+            check_cancel(cancel)?;
+
+            let chunk = df.slice(offset as i64, FILL_CHUNK_SIZE);
+
+            let mut values: HashMap<&str, Float64Chunked> = HashMap::new();
+            for &column in &value_columns {
+                values.insert(column, chunk.column(column)?.cast(&DataType::Float64)?.f64()?.clone());
+            }
+
+            let mut selections: HashMap<&str, BooleanChunked> = HashMap::new();
+            for &column in &selection_columns {
+                selections.insert(column, chunk.column(column)?.bool()?.clone());
+            }
+
+            // Iterator over whether each event passes the selection
+            let selected = |selection: &Option<String>| -> Box<dyn Iterator<Item = bool> + '_> {
+                match selection {
+                    Some(selection) => Box::new(selections[selection.as_str()].into_iter().map(|passed| passed == Some(true))),
+                    None => Box::new(std::iter::repeat(true)),
+                }
+            };
+
+            for fill in &batch {
+                match fill {
+                    BatchFill::Hist1D { name, column, selection } => {
+                        if let Some(HistogramTypes::Hist1D(hist)) = self.histogram_list.get_mut(name) {
+                            for (value, passed) in values[column.as_str()].into_iter().zip(selected(selection)) {
+                                // Events without a value (the sentinel) aren't counted, not even as underflow
+                                if let (Some(value), true) = (value, passed) {
+                                    if self.fill_invalid || value != self.sentinel {
+                                        hist.fill(value);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    BatchFill::Hist2D { name, x_column, y_column, selection } => {
+                        if let Some(HistogramTypes::Hist2D(hist)) = self.histogram_list.get_mut(name) {
+                            let points = values[x_column.as_str()].into_iter().zip(&values[y_column.as_str()]);
+                            for ((x_value, y_value), passed) in points.zip(selected(selection)) {
+                                if let (Some(x_value), Some(y_value), true) = (x_value, y_value, passed) {
+                                    // Same as fill_hist2d, events without a value in either column are skipped
+                                    if self.fill_invalid || (x_value != self.sentinel && y_value != self.sentinel) {
+                                        hist.fill(x_value, y_value);
+                                    }
+                                }
+                            }
+                        }
                     }
                 }
-            },
-            Err(e) => {
-                // Handle the error, for example, log it or return an error
-                eprintln!("Failed to collect LazyFrame: {}", e);
-                false
             }
+
+            progress(FillProgress {
+                reading: false,
+                events_filled: offset + chunk.height(),
+                total_events,
+                histogram_counts: batch.iter()
+                    .map(|fill| match fill {
+                        BatchFill::Hist1D { name, .. } | BatchFill::Hist2D { name, .. } => name,
+                    })
+                    .map(|name| (name.clone(), self.total_counts(name).unwrap_or(0)))
+                    .collect(),
+            });
         }
-    }
 
-    // Adds and fills a 2D histogram with data from Polars LazyFrame columns.
-    pub fn add_fill_hist2d(&mut self, name: &str, lf: &LazyFrame, x_column_name: &str, x_bins: usize, x_range: (f64, f64), y_column_name: &str, y_bins: usize, y_range: (f64, f64)) {
-        self.add_hist2d(name, x_bins, x_range, y_bins, y_range); // Add the histogram.
-        self.fill_hist2d(name, lf, x_column_name, y_column_name); // Fill it with data.
+        // Same as add_fill_hist1d/2d, an empty histogram is reported but isn't an error
+        for fill in &batch {
+            let (BatchFill::Hist1D { name, .. } | BatchFill::Hist2D { name, .. }) = fill;
+            if self.total_counts(name) == Some(0) {
+                self.report_fill_status(name, &FillStatus::NoEvents);
+            }
+        }
+
+        Ok(())
     }
 
     // Generates a heatmap using the `egui` library based on a 2D histogram.
-    pub fn egui_heatmap(&self, name: &str) -> Option<BarChart> {
+    pub fn egui_heatmap(&self, name: &str, scale: ColorScale) -> Option<Heatmap> {
         if let Some(HistogramTypes::Hist2D(hist)) = self.histogram_list.get(name) {
+            // One bar per occupied bin, so very large heatmaps are merged to keep the UI responsive
+            let mut cache = self.heatmap_cache.borrow_mut();
+            if cache.get(name).is_none_or(|cached| cached.max_bars != self.max_heatmap_bars) {
+                let factor = hist.rebin_factor_for(self.max_heatmap_bars);
+                let rebinned = (factor > 1).then(|| hist.rebinned(factor));
+                cache.insert(name.to_string(), RebinnedHeatmap { max_bars: self.max_heatmap_bars, factor, rebinned });
+            }
+            let RebinnedHeatmap { factor, rebinned, .. } = &cache[name];
+            let factor = *factor;
+            let hist = rebinned.as_ref().unwrap_or(hist);
+
             let bars_data = hist.generate_bar_data();           
             let mut bars = Vec::new();
 
-            let min: u32 = hist.min_count;
-            let max: u32 = hist.max_count;
+            let min: u64 = hist.min_count;
+            let max: u64 = hist.max_count;
             for bar_data in bars_data {
 
-                let color: Color32 = viridis_colormap(bar_data.count, min, max); // Determine color based on the count, using a colormap.
+                let color: Color32 = viridis_colormap(bar_data.count, min, max, scale); // Determine color based on the count, using a colormap.
                 
                 let bar = Bar {
                     orientation: Orientation::Vertical,
@@ -190,7 +782,11 @@ impl Histogrammer {
             }
     
             // Return a BarChart object if the histogram exists, otherwise return None.
-            Some(BarChart::new(bars).name(name))
+            Some(Heatmap {
+                bar_chart: BarChart::new(bars).name(name),
+                rebin_factor: factor,
+                count_range: (min, max),
+            })
         } else {
             None
         }
@@ -198,13 +794,92 @@ impl Histogrammer {
         
 }
 
-fn viridis_colormap(value: u32, min: u32, max: u32) -> Color32 {
-    // Handle case where min == max to avoid division by zero
-    let normalized: f64 = if max > min {
-        (value as f64 - min as f64) / (max as f64 - min as f64)
-    } else {
-        0.0
-    }.clamp(0.0, 1.0);
+// Collects the selected columns and calls fill with the f64 values of each event, returning the number of events.
+// No events is not an error, a conversion that fails or doesn't give contiguous rows is.
+fn for_each_event(lf: LazyFrame, mut fill: impl FnMut(&[f64])) -> Result<usize, PolarsError> {
+    let df = lf.collect()?;
+    if df.height() == 0 {
+        return Ok(0);
+    }
+
+    let values = df.to_ndarray::<Float64Type>(IndexOrder::C)?;
+    let values = values.as_slice()
+        .ok_or_else(|| PolarsError::ComputeError("the columns weren't converted to contiguous rows".into()))?;
+    for event in values.chunks(df.width()) {
+        fill(event);
+    }
+
+    Ok(df.height())
+}
+
+// Expands a range so the bin width is the nearest 1-2-5 round number (halfway goes to the larger one) and the
+// edges are multiples of it. The number of bins is adjusted to stay close to the requested number.
+pub fn nice_range(range: (f64, f64), bins: usize) -> ((f64, f64), usize) {
+    if range.1 <= range.0 || !range.0.is_finite() || !range.1.is_finite() || bins == 0 {
+        return (range, bins);
+    }
+
+    let raw_width = (range.1 - range.0) / bins as f64;
+    let magnitude = 10f64.powf(raw_width.log10().floor());
+    let nice_width = match raw_width / magnitude {
+        f if f < 1.5 => 1.0,
+        f if f < 3.5 => 2.0,
+        f if f < 7.5 => 5.0,
+        _ => 10.0,
+    } * magnitude;
+
+    let start = (range.0 / nice_width).floor() * nice_width;
+    let end = (range.1 / nice_width).ceil() * nice_width;
+    let nice_bins = ((end - start) / nice_width).round() as usize;
+
+    ((start, end), nice_bins)
+}
+
+// How heatmap counts are mapped onto the colormap. Log and gamma < 1 bring out weak structure next to
+// strong peaks, e.g. in particle ID plots.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColorScale {
+    Linear,
+    Log,
+    Gamma(f64), // count^gamma, 0.5 is a square root scale
+}
+
+impl ColorScale {
+    // Position of a count on the colormap, from 0 at min to 1 at max
+    pub fn normalize(self, value: u64, min: u64, max: u64) -> f64 {
+        // Handle case where min == max to avoid division by zero
+        if max <= min {
+            return 0.0;
+        }
+
+        let (value, min, max) = (value as f64, min as f64, max as f64);
+        match self {
+            ColorScale::Linear => (value - min) / (max - min),
+            // Occupied bins have at least one count
+            ColorScale::Log => (value.max(1.0) / min.max(1.0)).ln() / (max / min.max(1.0)).ln(),
+            ColorScale::Gamma(gamma) => (value.powf(gamma) - min.powf(gamma)) / (max.powf(gamma) - min.powf(gamma)),
+        }
+    }
+
+    // Count at a position on the colormap, the inverse of normalize. Used to label the colorbar.
+    pub fn count_at(self, fraction: f64, min: u64, max: u64) -> f64 {
+        let (min, max) = (min as f64, max as f64);
+        match self {
+            ColorScale::Linear => min + fraction * (max - min),
+            ColorScale::Log => min.max(1.0) * (max / min.max(1.0)).powf(fraction),
+            ColorScale::Gamma(gamma) => (min.powf(gamma) + fraction * (max.powf(gamma) - min.powf(gamma))).powf(1.0 / gamma),
+        }
+    }
+}
+
+fn viridis_colormap(value: u64, min: u64, max: u64, scale: ColorScale) -> Color32 {
+    viridis_color(scale.normalize(value, min, max))
+}
+
+// Color of the heatmap colormap at a position from 0 to 1.
+#[allow(clippy::excessive_precision)]
+pub fn viridis_color(normalized: f64) -> Color32 {
+    let normalized = normalized.clamp(0.0, 1.0);
 
     // Key colors from the Viridis colormap
     let viridis_colors: [(f32, f32, f32); 32] = [
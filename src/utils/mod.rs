@@ -4,4 +4,6 @@ pub mod plot_manager;
 pub mod cut;    
 pub mod egui_polygon;
 pub mod histogram1d;
-pub mod histogram2d;
\ No newline at end of file
+pub mod histogram2d;
+pub mod settings;
+pub mod session;
\ No newline at end of file
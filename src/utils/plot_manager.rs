@@ -1,13 +1,204 @@
-use super::histogrammer::{Histogrammer, HistogramTypes};
-use egui_plot::{Plot, Legend, Text, PlotPoint};
+use super::histogrammer::{Histogrammer, HistogramTypes, ColorScale, Subsystem, viridis_color};
+use egui_plot::{Plot, PlotBounds, PlotPoints, PlotUi, Legend, Line, LineStyle, MarkerShape, Points, Text, PlotPoint, VLine, HLine};
 use eframe::egui::{self, Color32};
 
+use std::collections::HashMap;
+
+use serde::{Serialize, Deserialize};
+
+use rfd::FileDialog;
+
 use crate::utils::cut::CutHandler;
+use crate::utils::histogram1d::{Histogram, HistogramView};
+use crate::utils::histogram2d::Gaussian2DFit;
+
+// Position spectra of the events reconstructed with both planes and of all events (Xavg, or the plane that
+// fired without it). Their ratio is the two plane efficiency against the focal plane position.
+const EFFICIENCY_HISTOGRAMS: (&str, &str) = ("Xavg: bothplanes", "Xavg: anyplane");
+
+// Number of bins shown on each side of the peak by "Go to Peak"
+const PEAK_WINDOW_HALF_BINS: f64 = 25.0;
+
+// Bin edges closer together than this (in pixels) are not drawn
+const MIN_BIN_EDGE_SPACING: f64 = 6.0;
+
+// Bin edges of a histogram axis that fall inside the visible part of the plot.
+fn visible_bin_edges(range: (f64, f64), bin_width: f64, view_min: f64, view_max: f64) -> Vec<f64> {
+    let bins = ((range.1 - range.0) / bin_width).round() as i64;
+    let first = ((view_min - range.0) / bin_width).ceil().max(0.0) as i64;
+    let last = (((view_max - range.0) / bin_width).floor() as i64).min(bins);
+
+    (first..=last).map(|edge| range.0 + edge as f64 * bin_width).collect()
+}
+
+// Space on the right of the plot for the heatmap colorbar, labels included
+const COLORBAR_WIDTH: f32 = 70.0;
+
+// Vertical strip of the heatmap colormap, from the lowest count at the bottom to the highest at the top,
+// with the counts at five evenly spaced ticks.
+fn draw_colorbar(ui: &egui::Ui, rect: egui::Rect, count_range: (u64, u64), scale: ColorScale) {
+    let painter = ui.painter_at(rect);
+    let strip = egui::Rect::from_min_max(
+        egui::pos2(rect.min.x + 6.0, rect.min.y + 10.0),
+        egui::pos2(rect.min.x + 22.0, rect.max.y - 10.0),
+    );
+
+    let steps = 64;
+    let step_height = strip.height() / steps as f32;
+    for step in 0..steps {
+        let bottom = strip.max.y - step as f32 * step_height;
+        let step_rect = egui::Rect::from_min_max(egui::pos2(strip.min.x, bottom - step_height), egui::pos2(strip.max.x, bottom));
+        painter.rect_filled(step_rect, 0.0, viridis_color((step as f64 + 0.5) / steps as f64));
+    }
+    painter.rect_stroke(strip, 0.0, ui.visuals().widgets.noninteractive.bg_stroke);
+
+    let (min, max) = count_range;
+    let text_color = ui.visuals().text_color();
+    for tick in 0..=4 {
+        let fraction = tick as f64 / 4.0;
+        let y = strip.max.y - fraction as f32 * strip.height();
+        let count = scale.count_at(fraction, min, max);
+        painter.line_segment([egui::pos2(strip.max.x, y), egui::pos2(strip.max.x + 4.0, y)], egui::Stroke::new(1.0, text_color));
+        painter.text(egui::pos2(strip.max.x + 6.0, y), egui::Align2::LEFT_CENTER, format!("{:.0}", count), egui::FontId::proportional(11.0), text_color);
+    }
+}
+
+// Colors of the plot area only, drawn over the app theme. Light and high contrast read better on a projector.
+#[derive(Clone, Copy, PartialEq)]
+enum PlotTheme {
+    App, // whatever the app theme uses
+    Light,
+    Dark,
+    HighContrast,
+}
+
+impl PlotTheme {
+    fn label(self) -> &'static str {
+        match self {
+            PlotTheme::App => "App",
+            PlotTheme::Light => "Light",
+            PlotTheme::Dark => "Dark",
+            PlotTheme::HighContrast => "High Contrast",
+        }
+    }
+
+    // egui_plot draws the background with extreme_bg_color, and the gridlines, axis labels and legend text
+    // with the text color.
+    fn apply(self, visuals: &mut egui::Visuals) {
+        let (background, foreground) = match self {
+            PlotTheme::App => return,
+            PlotTheme::Light => (Color32::WHITE, Color32::from_gray(20)),
+            PlotTheme::Dark => (Color32::from_gray(12), Color32::from_gray(200)),
+            PlotTheme::HighContrast => (Color32::BLACK, Color32::WHITE),
+        };
+        visuals.dark_mode = self != PlotTheme::Light;
+        visuals.extreme_bg_color = background;
+        visuals.override_text_color = Some(foreground);
+        visuals.widgets.noninteractive.bg_stroke.color = foreground;
+    }
+
+    // Colors of the histograms, in selection order
+    fn series_colors(self) -> [Color32; 5] {
+        match self {
+            PlotTheme::App | PlotTheme::Dark => [
+                Color32::LIGHT_BLUE,
+                Color32::LIGHT_RED,
+                Color32::LIGHT_GREEN,
+                Color32::LIGHT_YELLOW,
+                Color32::LIGHT_GRAY,
+            ],
+            PlotTheme::Light => [
+                Color32::from_rgb(0, 70, 200),
+                Color32::from_rgb(200, 20, 20),
+                Color32::from_rgb(0, 130, 40),
+                Color32::from_rgb(220, 120, 0),
+                Color32::from_rgb(120, 40, 160),
+            ],
+            PlotTheme::HighContrast => [
+                Color32::from_rgb(0, 255, 255),
+                Color32::from_rgb(255, 255, 0),
+                Color32::from_rgb(255, 0, 255),
+                Color32::from_rgb(0, 255, 0),
+                Color32::from_rgb(255, 128, 0),
+            ],
+        }
+    }
+
+    // Fits, annotations, bin edges and the crosshair
+    fn overlay_color(self) -> Color32 {
+        match self {
+            PlotTheme::Light => Color32::BLACK,
+            _ => Color32::WHITE,
+        }
+    }
+}
+
+// Color scale choice in the toolbar, the gamma is a separate field so it is kept when switching
+#[derive(Clone, Copy, PartialEq)]
+enum HeatmapScale {
+    Linear,
+    Log,
+    Gamma,
+}
+
+// Opacity of the bar fills of overlaid histograms, unless set for the histogram
+const OVERLAY_FILL_OPACITY: f32 = 0.4;
+
+// Labels closer than this (in pixels) to the pointer can be dragged or deleted
+const ANNOTATION_GRAB_RADIUS: f32 = 10.0;
+
+// Free text label placed at plot coordinates
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Annotation {
+    pub x: f64,
+    pub y: f64,
+    pub text: String,
+}
+
+// A named set of overlaid histograms and display options that can be restored in one click
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedView {
+    pub name: String,
+    pub histograms: Vec<String>,
+    pub show_bin_edges: bool,
+    pub show_crosshair: bool,
+    #[serde(default)]
+    pub annotations: Vec<Annotation>,
+}
 
 pub struct PlotManager {
     pub histogrammer: Histogrammer,
     selected_histograms: Vec<String>,
+    subsystem_filter: Option<Subsystem>, // only list the histograms of this subsystem, None lists all
     pub cutter: CutHandler,
+    goto_peak: bool,
+    gaussian_2d_fit: Option<(String, Gaussian2DFit)>,
+    baselines: HashMap<String, Histogram>, // pinned copies kept across reloads
+    show_baselines: bool,
+    show_bin_edges: bool,
+    show_crosshair: bool,
+    show_info: bool,
+    show_drift: bool,
+    show_efficiency: bool,
+    efficiency_rebin: usize, // bins merged before dividing, for fewer points with smaller errors
+    pub views: Vec<SavedView>,
+    new_view_name: String,
+    pub per_file_request: Option<Vec<String>>, // 1D histograms to rebuild from each file, handled by the app
+    pub cut_refill_request: Option<Vec<String>>, // histograms to refill with the events in the active cut, handled by the app
+    pub annotations: Vec<Annotation>,
+    annotate: bool, // clicks on the plot place labels instead of cut vertices
+    show_annotations: bool,
+    dragged_annotation: Option<usize>,
+    focus_annotation: Option<usize>, // newly placed label, to type into
+    draw_bars: bool, // filled bars instead of step lines for 1D histograms
+    fill_opacity: HashMap<String, f32>, // bar fill opacity set for a histogram, otherwise see default_fill_opacity
+    heatmap_scale: HeatmapScale,
+    heatmap_gamma: f64, // kept while another scale is selected
+    equal_aspect: bool, // same scale on both axes of a heatmap, so shapes aren't stretched
+    export_rebin: usize, // bins merged into one in exported CSV files
+    pub live_counts: HashMap<String, u64>, // counts of the histograms being built in the background
+    histogram_views: HashMap<String, HistogramView>, // how each 1D histogram was last viewed
+    plot_theme: PlotTheme,
 }
 
 impl PlotManager {
@@ -17,10 +208,69 @@ impl PlotManager {
         Self {
             histogrammer,
             selected_histograms: Vec::new(),
+            subsystem_filter: None,
             cutter,
+            goto_peak: false,
+            gaussian_2d_fit: None,
+            baselines: HashMap::new(),
+            show_baselines: true,
+            show_bin_edges: false,
+            show_crosshair: false,
+            show_info: false,
+            show_drift: false,
+            show_efficiency: false,
+            efficiency_rebin: 1,
+            views: Vec::new(),
+            new_view_name: String::new(),
+            per_file_request: None,
+            cut_refill_request: None,
+            annotations: Vec::new(),
+            annotate: false,
+            show_annotations: false,
+            dragged_annotation: None,
+            focus_annotation: None,
+            draw_bars: false,
+            fill_opacity: HashMap::new(),
+            heatmap_scale: HeatmapScale::Linear,
+            heatmap_gamma: 0.5,
+            equal_aspect: false,
+            export_rebin: 1,
+            live_counts: HashMap::new(),
+            histogram_views: HashMap::new(),
+            plot_theme: PlotTheme::App,
+        }
+    }
+
+    // Swaps in the histograms of a finished build. A build fills its own Histogrammer on the worker thread
+    // and only hands it over when done, so the plot always shows either the old or the new histograms
+    // complete, never a mix. Anything computed from the old histograms goes with them.
+    pub fn replace_histogrammer(&mut self, histogrammer: Histogrammer) {
+        self.histogrammer = histogrammer;
+        self.gaussian_2d_fit = None;
+        self.live_counts.clear();
+    }
+
+    // Selects the per file copies ("{name} [{stem}]") of the given histograms, in file order.
+    pub fn select_per_file_histograms(&mut self, names: &[String]) {
+        let per_file: Vec<String> = names.iter()
+            .flat_map(|name| self.histogrammer.per_file_stems.iter().map(move |stem| format!("{} [{}]", name, stem)))
+            .filter(|name| self.histogrammer.histogram_list.contains_key(name))
+            .collect();
+
+        if !per_file.is_empty() {
+            self.selected_histograms = per_file;
         }
     }
 
+    // Per file histograms get the same color for the same file, whatever else is selected.
+    fn per_file_color(&self, name: &str) -> Option<Color32> {
+        let index = self.histogrammer.per_file_stems.iter()
+            .position(|stem| name.ends_with(&format!(" [{}]", stem)))?;
+        // Golden ratio steps keep neighbouring files apart in hue
+        let hue = (index as f32 * 0.618_034).fract();
+        Some(egui::ecolor::Hsva::new(hue, 0.6, 1.0, 1.0).into())
+    }
+
     fn get_histogram_list(&self) -> Vec<String> {
         // Retrieves a sorted list of histogram names.
         let mut histogram_names: Vec<String> = self.histogrammer.histogram_list
@@ -35,18 +285,118 @@ impl PlotManager {
         self.histogrammer.histogram_list.get(name)
     }
 
+    // Saves the selected histograms and display options under a name, replacing a view with the same name.
+    fn save_view(&mut self, name: String) {
+        let view = SavedView {
+            name,
+            histograms: self.selected_histograms.clone(),
+            show_bin_edges: self.show_bin_edges,
+            show_crosshair: self.show_crosshair,
+            annotations: self.annotations.clone(),
+        };
+
+        match self.views.iter_mut().find(|existing| existing.name == view.name) {
+            Some(existing) => *existing = view,
+            None => self.views.push(view),
+        }
+    }
+
+    // Selects the histograms of a view that exist in the current load.
+    fn restore_view(&mut self, index: usize) {
+        let view = &self.views[index];
+        self.selected_histograms = view.histograms.iter()
+            .filter(|name| self.histogrammer.histogram_list.contains_key(*name))
+            .cloned()
+            .collect();
+        self.show_bin_edges = view.show_bin_edges;
+        self.show_crosshair = view.show_crosshair;
+        self.annotations = view.annotations.clone();
+    }
+
+    fn views_ui(&mut self, ui: &mut egui::Ui) {
+        ui.label("Views");
+
+        let mut restore_index = None;
+        let mut remove_index = None;
+
+        egui::ComboBox::from_id_source("saved_views")
+            .selected_text("Restore View")
+            .show_ui(ui, |ui| {
+                for (index, view) in self.views.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        if ui.selectable_label(false, &view.name).on_hover_text(view.histograms.join("\n")).clicked() {
+                            restore_index = Some(index);
+                        }
+                        if ui.small_button("X").on_hover_text("Delete view").clicked() {
+                            remove_index = Some(index);
+                        }
+                    });
+                }
+            });
+
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut self.new_view_name).hint_text("view name").desired_width(100.0));
+
+            let can_save = !self.new_view_name.trim().is_empty() && !self.selected_histograms.is_empty();
+            if ui.add_enabled(can_save, egui::Button::new("Save View"))
+                .on_hover_text("Save the selected histograms and display options")
+                .clicked() {
+                let name = self.new_view_name.trim().to_string();
+                self.save_view(name);
+                self.new_view_name.clear();
+            }
+        });
+
+        if let Some(index) = restore_index {
+            self.restore_view(index);
+        }
+
+        if let Some(index) = remove_index {
+            self.views.remove(index);
+        }
+
+        ui.separator();
+    }
+
     pub fn render_buttons(&mut self, ui: &mut egui::Ui) {
 
+        self.views_ui(ui);
+
         ui.label("Histograms"); // Label for the histogram buttons.
+
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.subsystem_filter, None, "All");
+            for subsystem in [Subsystem::Sps, Subsystem::Cebra] {
+                ui.radio_value(&mut self.subsystem_filter, Some(subsystem), subsystem.label());
+            }
+        });
         
-        let keys: Vec<String> = self.get_histogram_list(); // Retrieve the list of histogram names.
+        let mut keys: Vec<String> = self.get_histogram_list(); // Retrieve the list of histogram names.
+
+        // Histograms of a build in progress are listed too, disabled until the build finishes
+        for name in self.live_counts.keys() {
+            if !self.histogrammer.histogram_list.contains_key(name) {
+                keys.push(name.clone());
+            }
+        }
+        keys.sort();
+
+        // Histograms of a build in progress aren't tagged yet, so they are only listed under All
+        if let Some(subsystem) = self.subsystem_filter {
+            keys.retain(|name| self.histogrammer.subsystem_of(name) == Some(subsystem));
+        }
 
         // Layout for the buttons: top down and justified at the top.
         ui.with_layout(egui::Layout::top_down_justified(egui::Align::TOP), |ui| {
             for name in keys {
-                // Create a button for each histogram name.
-                let button: egui::Button<'_> = egui::Button::new(&name);
-                let response: egui::Response = ui.add(button); // Add the button to the UI and get the response.
+                // Create a button for each histogram name, with its count so far while building.
+                let button: egui::Button<'_> = match self.live_counts.get(&name) {
+                    Some(0) => egui::Button::new(egui::RichText::new(format!("{} (0)", name)).color(ui.visuals().warn_fg_color)),
+                    Some(count) => egui::Button::new(format!("{} ({})", name, count)),
+                    None => egui::Button::new(&name),
+                };
+                let ready = self.histogrammer.histogram_list.contains_key(&name);
+                let response: egui::Response = ui.add_enabled(ready, button); // Add the button to the UI and get the response.
 
                 // If the button is clicked, clear the current selection and select this histogram.
                 if response.clicked() {
@@ -55,15 +405,219 @@ impl PlotManager {
                 }
 
                 // If the button is right-clicked, add this histogram to the selection without clearing existing selections.
-                if response.secondary_clicked() {
-                    if !self.selected_histograms.contains(&name) {
-                        self.selected_histograms.push(name.clone());
-                    }
+                if response.secondary_clicked() && !self.selected_histograms.contains(&name) {
+                    self.selected_histograms.push(name.clone());
                 }
             }
         });
     }
 
+    // Plot bounds for a window centered on the maximum bin of a histogram.
+    fn peak_bounds(&self, name: &str) -> Option<PlotBounds> {
+        match self.get_histogram_type(name)? {
+            HistogramTypes::Hist1D(hist) => {
+                let (bin, count) = hist.max_bin()?;
+                let center = hist.bin_center(bin);
+                let half_width = PEAK_WINDOW_HALF_BINS * hist.bin_width;
+                // Heights as drawn, which depend on the log scale and normalization
+                let view = self.histogram_views.get(name).copied().unwrap_or_default();
+                let total_counts = hist.total_counts();
+                let count = if view.rebin > 1 { hist.rebinned(view.rebin).max_bin().map_or(count, |(_, count)| count) } else { count };
+                let (bottom, top) = (view.height(0.0, total_counts), view.height(count as f64 * 1.1, total_counts));
+                Some(PlotBounds::from_min_max([center - half_width, bottom.min(0.0)], [center + half_width, top]))
+            }
+            HistogramTypes::Hist2D(hist) => {
+                let ((x_index, y_index), _) = hist.max_bin()?;
+                let (x_center, y_center) = hist.bin_center(x_index, y_index);
+                let x_half_width = PEAK_WINDOW_HALF_BINS * hist.x_bin_width;
+                let y_half_width = PEAK_WINDOW_HALF_BINS * hist.y_bin_width;
+                Some(PlotBounds::from_min_max([x_center - x_half_width, y_center - y_half_width], [x_center + x_half_width, y_center + y_half_width]))
+            }
+        }
+    }
+
+    // Fits the first selected 2D histogram inside the active cut.
+    fn fit_gaussian_2d_in_active_cut(&mut self) {
+        self.gaussian_2d_fit = None;
+
+        let Some(cut) = self.cutter.active_cut() else {
+            return;
+        };
+
+        for name in self.selected_histograms.iter() {
+            if let Some(HistogramTypes::Hist2D(hist)) = self.histogrammer.histogram_list.get(name) {
+                self.gaussian_2d_fit = hist.fit_gaussian_2d(cut).map(|fit| (name.clone(), fit));
+                return;
+            }
+        }
+    }
+
+    // Keeps a copy of the selected 1D histograms to compare against after the next load.
+    fn pin_baselines(&mut self) {
+        for name in self.selected_histograms.iter() {
+            if let Some(HistogramTypes::Hist1D(hist)) = self.histogrammer.histogram_list.get(name) {
+                self.baselines.insert(name.clone(), hist.clone());
+            }
+        }
+    }
+
+    // Tab separated statistics over the full range, one row per selected histogram, with the 2D fit if
+    // it belongs to that histogram.
+    fn stats_table(&self) -> String {
+        let mut table = String::from("Name\tCounts\tMean X\tStdev X\tMean Y\tStdev Y\tFit Centroid X\tFit Centroid Y\tFit Sigma X\tFit Sigma Y\tFit Correlation\n");
+
+        for name in self.selected_histograms.iter() {
+            let row = match self.get_histogram_type(name) {
+                Some(HistogramTypes::Hist1D(hist)) => {
+                    let (counts, mean, stdev) = hist.stats(hist.range.0, hist.range.1);
+                    format!("{}\t{}\t{:.4}\t{:.4}\t\t\t\t\t\t\t", name, counts, mean, stdev)
+                }
+                Some(HistogramTypes::Hist2D(hist)) => {
+                    let (counts, mean_x, stdev_x, mean_y, stdev_y) = hist.stats(hist.x_range.0, hist.x_range.1, hist.y_range.0, hist.y_range.1);
+                    let fit = match &self.gaussian_2d_fit {
+                        Some((fit_name, fit)) if fit_name == name => format!("{:.4}\t{:.4}\t{:.4}\t{:.4}\t{:.4}", fit.x_mean, fit.y_mean, fit.x_sigma, fit.y_sigma, fit.correlation),
+                        _ => "\t\t\t\t".to_string(),
+                    };
+                    format!("{}\t{}\t{:.4}\t{:.4}\t{:.4}\t{:.4}\t{}", name, counts, mean_x, stdev_x, mean_y, stdev_y, fit)
+                }
+                None => continue,
+            };
+            table.push_str(&row);
+            table.push('\n');
+        }
+
+        table
+    }
+
+    // Window with the full statistics of each selected histogram.
+    fn info_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_info;
+
+        egui::Window::new("Histogram Info")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for name in self.selected_histograms.iter() {
+                        let entries = match self.get_histogram_type(name) {
+                            Some(HistogramTypes::Hist1D(hist)) => hist.info_entries(),
+                            Some(HistogramTypes::Hist2D(hist)) => hist.info_entries(),
+                            None => continue,
+                        };
+
+                        ui.heading(name);
+                        egui::Grid::new(("histogram_info", name)).striped(true).show(ui, |ui| {
+                            for (label, value) in entries {
+                                ui.label(label);
+                                ui.label(value);
+                                ui.end_row();
+                            }
+                        });
+                        ui.separator();
+                    }
+                });
+            });
+
+        self.show_info = open;
+    }
+
+    // Window comparing the main peak of each selected 1D histogram with its pinned baseline, e.g. a
+    // reference run loaded earlier, to follow gain drift between runs.
+    fn drift_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_drift;
+
+        egui::Window::new("Gain Drift")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.label("Main peak of each selected histogram compared to its baseline");
+
+                egui::Grid::new("gain_drift").striped(true).show(ui, |ui| {
+                    for header in ["Histogram", "Reference", "Current", "Shift", "Drift"] {
+                        ui.strong(header)
+                            .on_hover_text("Centroids of a Gaussian fit of the main peak, ± their statistical uncertainty σ/√N");
+                    }
+                    ui.end_row();
+
+                    for name in self.selected_histograms.iter() {
+                        let (Some(HistogramTypes::Hist1D(hist)), Some(baseline)) = (self.get_histogram_type(name), self.baselines.get(name)) else {
+                            continue;
+                        };
+
+                        ui.label(name);
+
+                        match (baseline.fit_main_peak(), hist.fit_main_peak()) {
+                            (Some(reference), Some(current)) => {
+                                let shift = current.centroid - reference.centroid;
+                                let shift_error = reference.centroid_error.hypot(current.centroid_error);
+                                for fit in [reference, current] {
+                                    ui.label(format!("{:.2} ± {:.2}", fit.centroid, fit.centroid_error))
+                                        .on_hover_text(format!("Centroid ± σ/√N\nσ: {:.2}\n{} counts in the fitted bins", fit.sigma, fit.counts));
+                                }
+                                ui.label(format!("{:+.2} ± {:.2}", shift, shift_error));
+                                if reference.centroid != 0.0 {
+                                    ui.label(format!("{:+.3}%", 100.0 * shift / reference.centroid));
+                                } else {
+                                    ui.label("-");
+                                }
+                            }
+                            _ => {
+                                ui.label("No peak found");
+                            }
+                        }
+                        ui.end_row();
+                    }
+                });
+            });
+
+        self.show_drift = open;
+    }
+
+    // Window with the two plane efficiency (see EFFICIENCY_HISTOGRAMS) against the position, with binomial errors.
+    fn efficiency_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_efficiency;
+        let (numerator, denominator) = EFFICIENCY_HISTOGRAMS;
+
+        egui::Window::new("Two Plane Efficiency")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                let (Some(HistogramTypes::Hist1D(passed)), Some(HistogramTypes::Hist1D(total))) = (self.histogrammer.histogram_list.get(numerator), self.histogrammer.histogram_list.get(denominator)) else {
+                    ui.label(format!("Needs the '{}' and '{}' histograms", numerator, denominator));
+                    return;
+                };
+
+                ui.horizontal(|ui| {
+                    ui.label(format!("{} / {}", numerator, denominator));
+                    ui.add(egui::DragValue::new(&mut self.efficiency_rebin).speed(0.1).clamp_range(1..=64).prefix("Rebin: "));
+                });
+
+                let (passed_counts, total_counts) = (passed.total_counts(), total.total_counts());
+                if total_counts > 0 {
+                    let overall = passed_counts as f64 / total_counts as f64;
+                    ui.label(format!("Overall: {:.4} ± {:.4} ({} of {} events)", overall, (overall * (1.0 - overall) / total_counts as f64).max(0.0).sqrt(), passed_counts, total_counts));
+                }
+
+                let Some(points) = passed.rebinned(self.efficiency_rebin).efficiency(&total.rebinned(self.efficiency_rebin)) else {
+                    ui.label("The two histograms have different binnings");
+                    return;
+                };
+
+                Plot::new("efficiency_plot")
+                    .height(300.0)
+                    .width(500.0)
+                    .x_axis_label(numerator.split(':').next().unwrap_or(numerator))
+                    .y_axis_label("Efficiency")
+                    .show(ui, |plot_ui| {
+                        let color = plot_ui.ctx().style().visuals.text_color();
+                        for &(x, ratio, error) in &points {
+                            plot_ui.line(Line::new(PlotPoints::new(vec![[x, ratio - error], [x, ratio + error]])).color(color));
+                        }
+                        let markers: Vec<[f64; 2]> = points.iter().map(|&(x, ratio, _)| [x, ratio]).collect();
+                        plot_ui.points(Points::new(markers).radius(2.0).color(color));
+                    });
+            });
+
+        self.show_efficiency = open;
+    }
+
     pub fn render_selected_histograms(&mut self, ui: &mut egui::Ui) {
         // Display a message if no histograms are selected.
         if self.selected_histograms.is_empty() {
@@ -71,6 +625,154 @@ impl PlotManager {
             return;
         }
 
+        ui.horizontal(|ui| {
+            if ui.button("Go to Peak").on_hover_text("Center the view on the maximum bin of the first selected histogram").clicked() {
+                self.goto_peak = true;
+            }
+
+            let can_fit = self.cutter.active_cut().is_some_and(|cut| cut.vertices.len() >= 3);
+            if ui.add_enabled(can_fit, egui::Button::new("Fit 2D Gaussian"))
+                .on_hover_text("Fit a 2D Gaussian to the first selected 2D histogram inside the active cut")
+                .on_disabled_hover_text("Draw a cut around the peak first")
+                .clicked() {
+                self.fit_gaussian_2d_in_active_cut();
+            }
+
+            if ui.button("Info").on_hover_text("Show the full statistics of the selected histograms").clicked() {
+                self.show_info = true;
+            }
+
+            let (numerator, denominator) = EFFICIENCY_HISTOGRAMS;
+            let has_efficiency = [numerator, denominator].iter().all(|name| matches!(self.get_histogram_type(name), Some(HistogramTypes::Hist1D(_))));
+            if ui.add_enabled(has_efficiency, egui::Button::new("Efficiency"))
+                .on_hover_text(format!("Two plane efficiency against the position: '{}' divided by '{}', bin by bin", numerator, denominator))
+                .on_disabled_hover_text(format!("Needs the '{}' and '{}' histograms", numerator, denominator))
+                .clicked() {
+                self.show_efficiency = true;
+            }
+
+            if ui.button("Copy Stats").on_hover_text("Copy the statistics of the selected histograms as tab separated rows, e.g. to paste into a spreadsheet").clicked() {
+                let stats = self.stats_table();
+                ui.output_mut(|output| output.copied_text = stats);
+            }
+
+            let per_file_names: Vec<String> = self.selected_histograms.iter()
+                .filter(|name| matches!(self.get_histogram_type(name), Some(HistogramTypes::Hist1D(_))) && self.per_file_color(name).is_none())
+                .cloned()
+                .collect();
+            if ui.add_enabled(!per_file_names.is_empty(), egui::Button::new("Build Per File"))
+                .on_hover_text("Reload, also building the selected 1D histograms from each file on its own, and overlay them, one color per file.\nThe next load doesn't build them again. A press during a load starts once it is done.")
+                .on_disabled_hover_text("Select a 1D histogram first")
+                .clicked() {
+                self.per_file_request = Some(per_file_names);
+            }
+
+            if ui.add_enabled(can_fit, egui::Button::new("Refill With Cut"))
+                .on_hover_text("Refill only the selected histograms with the events inside the active cut, without rebuilding the rest")
+                .on_disabled_hover_text("Draw a cut first")
+                .clicked() {
+                self.cut_refill_request = Some(self.selected_histograms.clone());
+            }
+
+            ui.separator();
+
+            if ui.button("Pin Baseline")
+                .on_hover_text("Keep a copy of the selected 1D histograms.\nAfter reloading, the copy is drawn dashed over the rebuilt histogram with the same name.")
+                .clicked() {
+                self.pin_baselines();
+            }
+
+            if !self.baselines.is_empty() {
+                ui.checkbox(&mut self.show_baselines, format!("Show Baselines ({})", self.baselines.len()));
+
+                if ui.button("Clear Baselines").clicked() {
+                    self.baselines.clear();
+                }
+
+                if ui.button("Gain Drift")
+                    .on_hover_text("Compare the main peak of the selected histograms with their baselines.\nPin the baseline while the reference run is loaded, then load the new run.")
+                    .clicked() {
+                    self.show_drift = true;
+                }
+            }
+
+            ui.separator();
+
+            ui.checkbox(&mut self.show_bin_edges, "Bin Edges")
+                .on_hover_text("Draw the bin edges of the first selected histogram, to line cuts up with the binning.\nHidden while the bins are too narrow on screen to tell apart.");
+
+            ui.checkbox(&mut self.show_crosshair, "Crosshair")
+                .on_hover_text("Lines through the pointer with its coordinates, to compare the same x across overlaid histograms");
+
+            ui.checkbox(&mut self.draw_bars, "Bars")
+                .on_hover_text("Draw 1D histograms as filled bars. Overlaid histograms are semi-transparent, see Opacity.");
+
+            if self.draw_bars {
+                self.fill_opacity_ui(ui);
+            }
+
+            self.histogram_view_ui(ui);
+
+            self.heatmap_scale_ui(ui);
+
+            ui.separator();
+
+            if ui.button("Export CSV").on_hover_text("Save the bin centers and counts of each selected 1D histogram").clicked() {
+                self.export_csv();
+            }
+            ui.add(egui::DragValue::new(&mut self.export_rebin).speed(0.1).clamp_range(1..=1024).prefix("Rebin: "))
+                .on_hover_text("Bins merged into one in the exported file. The histogram in the plot is not changed.");
+
+            ui.separator();
+
+            ui.checkbox(&mut self.annotate, "Annotate")
+                .on_hover_text("Click the plot to place a text label, drag a label to move it, right-click a label to delete it.\nThe active cut can't be edited while annotating.");
+
+            if ui.button(format!("Annotations ({})", self.annotations.len())).on_hover_text("Edit the text and position of the labels").clicked() {
+                self.show_annotations = true;
+            }
+
+            ui.separator();
+
+            egui::ComboBox::from_label("Plot Theme")
+                .selected_text(self.plot_theme.label())
+                .show_ui(ui, |ui| {
+                    for theme in [PlotTheme::App, PlotTheme::Light, PlotTheme::Dark, PlotTheme::HighContrast] {
+                        ui.selectable_value(&mut self.plot_theme, theme, theme.label());
+                    }
+                })
+                .response
+                .on_hover_text("Background, gridline and histogram colors of the plot, e.g. light or high contrast for a projector");
+        });
+
+        if self.histogrammer.sample_fraction < 1.0 {
+            ui.colored_label(ui.visuals().warn_fg_color, format!("Sampled build: {:.1}% of the events", self.histogrammer.sample_fraction * 100.0));
+        }
+
+        if self.show_annotations {
+            self.annotations_window(ui.ctx());
+        }
+
+        if self.show_info {
+            self.info_window(ui.ctx());
+        }
+
+        if self.show_drift {
+            self.drift_window(ui.ctx());
+        }
+
+        if self.show_efficiency {
+            self.efficiency_window(ui.ctx());
+        }
+
+        // Bounds are computed before the plot borrows the histograms.
+        let peak_bounds = if self.goto_peak {
+            self.goto_peak = false;
+            self.peak_bounds(&self.selected_histograms[0])
+        } else {
+            None
+        };
+
         // Set up the plot for the combined histogram display.
         let plot = Plot::new("Combined Histogram")
             .legend(Legend::default())
@@ -80,39 +782,106 @@ impl PlotManager {
             .allow_boxed_zoom(true)
             .allow_scroll(true);
 
-        
-        // Display the plot in the UI.
-        plot.show(ui, |plot_ui| {
-
-            // Define a set of colors for the histograms.
-            let colors: [Color32; 5] = [
-                Color32::LIGHT_BLUE, 
-                Color32::LIGHT_RED, 
-                Color32::LIGHT_GREEN, 
-                Color32::LIGHT_YELLOW, 
-                Color32::LIGHT_GRAY
-            ];
-
-            let plot_min_x = plot_ui.plot_bounds().min()[0];
-            let plot_max_x = plot_ui.plot_bounds().max()[0];
-            let plot_min_y = plot_ui.plot_bounds().min()[1];
-            let plot_max_y = plot_ui.plot_bounds().max()[1];
-
-            for (i, selected_name) in self.selected_histograms.iter().enumerate() {
-                // Render the appropriate histogram type based on its type.
-                match self.get_histogram_type(selected_name) {
-                    Some(HistogramTypes::Hist1D(hist)) => {
-
-                        // Render a 1D histogram as a step line.
-                        let hist_color = colors[i % colors.len()];
-                        // if let Some(step_line) = self.histogrammer.egui_histogram_step(selected_name, colors[i % colors.len()]) {
-                        if let Some(step_line) = self.histogrammer.egui_histogram_step(selected_name, hist_color) {
-
-                            plot_ui.line(step_line);
+        // Log scale counts are drawn as their log10, label the decades. The overlaid 1D histograms share
+        // one y axis, so all of them follow the log scale of the first.
+        let first_view = self.selected_histograms.iter()
+            .find(|name| matches!(self.get_histogram_type(name), Some(HistogramTypes::Hist1D(_))))
+            .map(|name| self.histogram_views.get(name).copied().unwrap_or_default());
+        let log_y = first_view.is_some_and(|view| view.log_y);
+        let plot = match first_view {
+            Some(first_view) if log_y => plot
+                .y_axis_formatter(|mark, _, _| {
+                    if mark.value.fract() == 0.0 { format!("1e{}", mark.value) } else { String::new() }
+                })
+                .label_formatter(move |name, value| {
+                    let counts = first_view.value_text(value.y);
+                    if name.is_empty() { format!("x = {:.2}\n{}", value.x, counts) } else { format!("{}\nx = {:.2}\n{}", name, value.x, counts) }
+                }),
+            _ => plot,
+        };
+
+        // Columns of the plotted heatmaps, the other cuts are only drawn on the columns they were made on
+        let plotted_columns: Vec<(String, String)> = self.selected_histograms.iter()
+            .filter_map(|name| self.histogrammer.source_columns(name))
+            .filter_map(|(columns, _)| match columns[..] {
+                [x_column, y_column] => Some((x_column.to_string(), y_column.to_string())),
+                _ => None,
+            })
+            .collect();
+
+        // Heatmaps get a colorbar on the right of the plot
+        let has_heatmap = self.selected_histograms.iter()
+            .any(|name| matches!(self.get_histogram_type(name), Some(HistogramTypes::Hist2D(_))));
+        let available_rect = ui.available_rect_before_wrap();
+        let plot = if has_heatmap {
+            plot.width((available_rect.width() - COLORBAR_WIDTH).max(0.0))
+        } else {
+            plot
+        };
+        let plot = if has_heatmap && self.equal_aspect {
+            plot.data_aspect(1.0)
+        } else {
+            plot
+        };
+
+        // Display the plot in the UI, with the plot theme applied to the plot area only.
+        let plot_response = ui.scope(|ui| {
+            self.plot_theme.apply(ui.visuals_mut());
+            plot.show(ui, |plot_ui| {
+                let mut colorbar_range = None;
+
+                if let Some(bounds) = peak_bounds {
+                    plot_ui.set_plot_bounds(bounds);
+                }
+
+                // Define a set of colors for the histograms.
+                let colors = self.plot_theme.series_colors();
+                let overlay_color = self.plot_theme.overlay_color();
+
+                let plot_min_x = plot_ui.plot_bounds().min()[0];
+                let plot_max_x = plot_ui.plot_bounds().max()[0];
+                let plot_min_y = plot_ui.plot_bounds().min()[1];
+                let plot_max_y = plot_ui.plot_bounds().max()[1];
+                let plot_width_pixels = plot_ui.transform().frame().width() as f64;
+
+                for (i, selected_name) in self.selected_histograms.iter().enumerate() {
+                    // Render the appropriate histogram type based on its type.
+                    match self.get_histogram_type(selected_name) {
+                        Some(HistogramTypes::Hist1D(hist)) => {
+
+                            // Render a 1D histogram as a step line, or as bars.
+                            let hist_color = self.per_file_color(selected_name).unwrap_or(colors[i % colors.len()]);
+                            // Fine binned histograms (e.g. 8000 bins) are drawn with about one step per pixel
+                            let group = hist.bins_per_pixel(plot_max_x - plot_min_x, plot_width_pixels);
+
+                            let view = HistogramView { log_y, ..self.histogram_views.get(selected_name).copied().unwrap_or_default() };
+
+                            let bar_chart = if self.draw_bars {
+                                self.histogrammer.egui_histogram_bars(selected_name, hist_color, &view, self.fill_opacity_of(selected_name))
+                            } else {
+                                None
+                            };
+
+                            if let Some(bar_chart) = bar_chart {
+                                plot_ui.bar_chart(bar_chart);
+                            } else if let Some(step_line) = self.histogrammer.egui_histogram_step(selected_name, hist_color, group, &view) {
+                                plot_ui.line(step_line);
+                            }
+
+                            // Pinned copy from an earlier load
+                            if let Some(baseline) = self.baselines.get(selected_name).filter(|_| self.show_baselines) {
+                                let plot_points: PlotPoints = baseline.view_step_points(&view, group).iter().map(|&(x, y)| [x, y]).collect();
+                                plot_ui.line(
+                                    Line::new(plot_points)
+                                        .color(hist_color)
+                                        .style(LineStyle::dashed_loose())
+                                        .name(format!("{} (baseline)", selected_name))
+                                );
+                            }
 
                             let stats_entries = hist.legend_entries(plot_min_x, plot_max_x);
 
-                            for (_i, entry) in stats_entries.iter().enumerate() {
+                            for entry in stats_entries.iter() {
                                 plot_ui.text(
                                     Text::new(PlotPoint::new(0, 0), " ") // Placeholder for positioning; adjust as needed
                                         .highlight(false)
@@ -120,42 +889,348 @@ impl PlotManager {
                                         .name(entry)
                                 );
                             }
+                        }
+                        Some(HistogramTypes::Hist2D(hist)) => {
+                            
+                            let hist_color = colors[i % colors.len()];
+
+                            // Render a 2D histogram as a heatmap.
+                            if let Some(heatmap) = self.histogrammer.egui_heatmap(selected_name, self.heatmap_scale()) {
+                                plot_ui.bar_chart(heatmap.bar_chart);
+                                // An empty histogram has no count range
+                                if heatmap.count_range.0 <= heatmap.count_range.1 {
+                                    colorbar_range.get_or_insert(heatmap.count_range);
+                                }
+
+                                let mut stats_entries = hist.legend_entries(plot_min_x, plot_max_x, plot_min_y, plot_max_y);
+                                if heatmap.rebin_factor > 1 {
+                                    stats_entries.push(format!("Drawn with {0}x{0} bins merged (too many bins to draw)", heatmap.rebin_factor));
+                                }
+
+                                // With different scales on the axes the bins aren't square on screen, which stretches shapes
+                                if !self.equal_aspect {
+                                    let [x_pixels_per_unit, y_pixels_per_unit] = plot_ui.transform().dpos_dvalue();
+                                    let bin_pixels = (hist.x_bin_width * x_pixels_per_unit.abs(), hist.y_bin_width * y_pixels_per_unit.abs());
+                                    stats_entries.push(format!(
+                                        "Bins: {} x {} of {:.3} x {:.3}, drawn {:.1} x {:.1} px",
+                                        hist.x_bins, hist.y_bins, hist.x_bin_width, hist.y_bin_width, bin_pixels.0, bin_pixels.1
+                                    ));
+                                }
+
+                                for entry in stats_entries.iter() {
+                                    plot_ui.text(
+                                        Text::new(PlotPoint::new(0, 0), " ") // Placeholder for positioning; adjust as needed
+                                            .highlight(false)
+                                            .color(hist_color)
+                                            .name(entry)
+                                    );
+                                }
+
+                            }
+                        }
 
+                        None => {
+                            // Optionally handle the case where the histogram is not found or its type is not supported.
+                            // ui.label(format!("Histogram '{}' not found or type not supported.", selected_name));
                         }
                     }
-                    Some(HistogramTypes::Hist2D(hist)) => {
-                        
-                        let hist_color = colors[i % colors.len()];
+                }
 
-                        // Render a 2D histogram as a heatmap.
-                        if let Some(bar_chart) = self.histogrammer.egui_heatmap(selected_name) {
-                            plot_ui.bar_chart(bar_chart);
+                // Mark the centroid and 1 sigma ellipse of the 2D fit while its histogram is shown
+                if let Some((name, fit)) = &self.gaussian_2d_fit {
+                    if self.selected_histograms.contains(name) {
+                        plot_ui.line(Line::new(fit.sigma_ellipse(100)).color(overlay_color).name("2D Fit"));
+                        plot_ui.points(
+                            Points::new(vec![[fit.x_mean, fit.y_mean]])
+                                .shape(MarkerShape::Cross)
+                                .radius(8.0)
+                                .color(overlay_color)
+                                .name(format!("Centroid: ({:.2}, {:.2})\nSigma: ({:.2}, {:.2})\nCorrelation: {:.3}\nCounts: {}", fit.x_mean, fit.y_mean, fit.x_sigma, fit.y_sigma, fit.correlation, fit.counts))
+                        );
+                    }
+                }
 
-                            let stats_entries = hist.legend_entries(plot_min_x, plot_max_x, plot_min_y, plot_max_y);
+                if self.show_bin_edges {
+                    let edge_color = overlay_color.linear_multiply(40.0 / 255.0);
+                    let [x_pixels_per_unit, y_pixels_per_unit] = plot_ui.transform().dpos_dvalue();
 
-                            for (_i, entry) in stats_entries.iter().enumerate() {
-                                plot_ui.text(
-                                    Text::new(PlotPoint::new(0, 0), " ") // Placeholder for positioning; adjust as needed
-                                        .highlight(false)
-                                        .color(hist_color)
-                                        .name(entry)
-                                );
-                            }
+                    // x edges for both histogram types, y edges for 2D histograms
+                    let (x_axis, y_axis) = match self.get_histogram_type(&self.selected_histograms[0]) {
+                        Some(HistogramTypes::Hist1D(hist)) => (Some((hist.range, hist.bin_width)), None),
+                        Some(HistogramTypes::Hist2D(hist)) => (Some((hist.x_range, hist.x_bin_width)), Some((hist.y_range, hist.y_bin_width))),
+                        None => (None, None),
+                    };
 
+                    if let Some((range, bin_width)) = x_axis.filter(|(_, bin_width)| bin_width * x_pixels_per_unit.abs() >= MIN_BIN_EDGE_SPACING) {
+                        for x in visible_bin_edges(range, bin_width, plot_min_x, plot_max_x) {
+                            plot_ui.vline(VLine::new(x).color(edge_color).width(1.0));
                         }
                     }
 
-                    None => {
-                        // Optionally handle the case where the histogram is not found or its type is not supported.
-                        // ui.label(format!("Histogram '{}' not found or type not supported.", selected_name));
+                    if let Some((range, bin_width)) = y_axis.filter(|(_, bin_width)| bin_width * y_pixels_per_unit.abs() >= MIN_BIN_EDGE_SPACING) {
+                        for y in visible_bin_edges(range, bin_width, plot_min_y, plot_max_y) {
+                            plot_ui.hline(HLine::new(y).color(edge_color).width(1.0));
+                        }
                     }
                 }
+
+                if self.show_crosshair {
+                    if let Some(pointer) = plot_ui.pointer_coordinate() {
+                        let crosshair_color = overlay_color.linear_multiply(120.0 / 255.0);
+                        plot_ui.vline(VLine::new(pointer.x).color(crosshair_color).width(1.0));
+                        plot_ui.hline(HLine::new(pointer.y).color(crosshair_color).width(1.0));
+                        let y_text = match first_view {
+                            Some(first_view) if log_y => first_view.value_text(pointer.y),
+                            _ => format!("{:.2}", pointer.y),
+                        };
+                        plot_ui.text(
+                            Text::new(pointer, format!("({:.2}, {})", pointer.x, y_text))
+                                .anchor(egui::Align2::LEFT_BOTTOM)
+                                .color(crosshair_color)
+                        );
+                    }
+                }
+
+                self.draw_annotations(plot_ui);
+
+                // Clicks place labels while annotating, the cuts are still drawn
+                self.cutter.draw_cuts(plot_ui, !self.annotate, &plotted_columns);
+                if self.annotate {
+                    self.handle_annotation_input(plot_ui);
+                }
+
+                colorbar_range
+            })
+        }).inner;
+
+        // Colors of the first heatmap
+        if let Some(count_range) = plot_response.inner {
+            let plot_rect = plot_response.response.rect;
+            let colorbar_rect = egui::Rect::from_min_max(
+                egui::pos2(plot_rect.max.x, plot_rect.min.y),
+                egui::pos2(plot_rect.max.x + COLORBAR_WIDTH, plot_rect.max.y),
+            );
+            draw_colorbar(ui, colorbar_rect, count_range, self.heatmap_scale());
+        }
+    }
+
+    fn heatmap_scale(&self) -> ColorScale {
+        match self.heatmap_scale {
+            HeatmapScale::Linear => ColorScale::Linear,
+            HeatmapScale::Log => ColorScale::Log,
+            HeatmapScale::Gamma => ColorScale::Gamma(self.heatmap_gamma),
+        }
+    }
+
+    // Color scale of the heatmaps, shown while a 2D histogram is selected
+    fn heatmap_scale_ui(&mut self, ui: &mut egui::Ui) {
+        if !self.selected_histograms.iter().any(|name| matches!(self.get_histogram_type(name), Some(HistogramTypes::Hist2D(_)))) {
+            return;
+        }
+
+        ui.separator();
+
+        ui.label("Colors: ").on_hover_text("How the counts of the heatmap are mapped onto the colormap");
+        ui.radio_value(&mut self.heatmap_scale, HeatmapScale::Linear, "Linear");
+        ui.radio_value(&mut self.heatmap_scale, HeatmapScale::Log, "Log");
+        ui.radio_value(&mut self.heatmap_scale, HeatmapScale::Gamma, "Gamma")
+            .on_hover_text("Color by count^gamma. A gamma of 0.5 (square root) sits between linear and log.");
+        if self.heatmap_scale == HeatmapScale::Gamma {
+            ui.add(egui::DragValue::new(&mut self.heatmap_gamma).speed(0.01).clamp_range(0.05..=3.0).prefix("gamma: "));
+        }
+
+        ui.checkbox(&mut self.equal_aspect, "Equal Aspect")
+            .on_hover_text("One unit is the same length on both axes, so peaks keep their shape.\nWhen off, the legend shows the size of a bin on screen.");
+    }
+
+    // Opaque for a single histogram, semi-transparent when overlaid so the fills blend
+    fn default_fill_opacity(&self) -> f32 {
+        if self.selected_histograms.len() > 1 { OVERLAY_FILL_OPACITY } else { 1.0 }
+    }
+
+    fn fill_opacity_of(&self, name: &str) -> f32 {
+        self.fill_opacity.get(name).copied().unwrap_or_else(|| self.default_fill_opacity())
+    }
+
+    // Opacity of the bar fill of each selected 1D histogram
+    fn fill_opacity_ui(&mut self, ui: &mut egui::Ui) {
+        let names: Vec<String> = self.selected_histograms.iter()
+            .filter(|name| matches!(self.get_histogram_type(name), Some(HistogramTypes::Hist1D(_))))
+            .cloned()
+            .collect();
+
+        ui.menu_button("Opacity", |ui| {
+            for name in names.iter() {
+                let mut opacity = self.fill_opacity_of(name);
+                if ui.add(egui::Slider::new(&mut opacity, 0.0..=1.0).text(name)).changed() {
+                    self.fill_opacity.insert(name.clone(), opacity);
+                }
             }
 
-            self.cutter.draw_active_cut(plot_ui);
-            
+            if ui.button("Reset").on_hover_text("Opaque for a single histogram, semi-transparent when overlaid").clicked() {
+                for name in names.iter() {
+                    self.fill_opacity.remove(name);
+                }
+            }
         });
     }
 
+    // Log scale, rebin and normalization of the selected 1D histograms. The controls show the view of the
+    // first one and a change is applied to all of them. Each histogram keeps its view when deselected.
+    fn histogram_view_ui(&mut self, ui: &mut egui::Ui) {
+        let names: Vec<String> = self.selected_histograms.iter()
+            .filter(|name| matches!(self.get_histogram_type(name), Some(HistogramTypes::Hist1D(_))))
+            .cloned()
+            .collect();
+        let Some(first) = names.first() else {
+            return;
+        };
+
+        ui.separator();
+
+        let mut view = self.histogram_views.get(first).copied().unwrap_or_default();
+        let before = view;
+
+        ui.checkbox(&mut view.log_y, "Log Y");
+        ui.add(egui::DragValue::new(&mut view.rebin).speed(0.1).clamp_range(1..=1024).prefix("Rebin: "))
+            .on_hover_text("Bins merged into one for drawing. The histogram itself is not changed.");
+        ui.checkbox(&mut view.normalize, "Normalize")
+            .on_hover_text("Divide by the total counts, to compare runs with different statistics");
+
+        if view != before {
+            for name in names {
+                self.histogram_views.insert(name, view);
+            }
+        }
+    }
+
+    // Asks for a file for each selected 1D histogram and writes it as CSV, rebinned by export_rebin.
+    fn export_csv(&self) {
+        for name in self.selected_histograms.iter() {
+            let Some(HistogramTypes::Hist1D(hist)) = self.get_histogram_type(name) else {
+                continue;
+            };
+
+            let Some(path) = FileDialog::new()
+                .set_title(format!("Export {}", name))
+                .set_file_name(format!("{}.csv", name.replace([':', '/', '\\'], "_")))
+                .add_filter("CSV Files", &["csv"])
+                .save_file() else {
+                continue;
+            };
+
+            if let Err(e) = std::fs::write(&path, hist.rebinned(self.export_rebin).to_csv()) {
+                eprintln!("Failed to export '{}' to {:?}: {}", name, path, e);
+            }
+        }
+    }
+
+    // Labels are drawn with a small marker at the point they refer to.
+    fn draw_annotations(&self, plot_ui: &mut PlotUi) {
+        for annotation in self.annotations.iter() {
+            plot_ui.points(
+                Points::new(vec![[annotation.x, annotation.y]])
+                    .shape(MarkerShape::Circle)
+                    .radius(2.0)
+                    .color(self.plot_theme.overlay_color())
+            );
+            plot_ui.text(
+                Text::new(PlotPoint::new(annotation.x, annotation.y), annotation.text.clone())
+                    .anchor(egui::Align2::LEFT_BOTTOM)
+                    .color(self.plot_theme.overlay_color())
+            );
+        }
+    }
+
+    // Click to place a label, drag a label to move it, right-click a label to delete it.
+    fn handle_annotation_input(&mut self, plot_ui: &mut PlotUi) {
+        let response = plot_ui.response().clone();
+        let Some(pointer) = plot_ui.pointer_coordinate() else {
+            return;
+        };
+
+        // Closest label under the pointer
+        let hovered = response.hover_pos().and_then(|pointer_pos| {
+            self.annotations.iter()
+                .enumerate()
+                .map(|(index, annotation)| (index, plot_ui.screen_from_plot(PlotPoint::new(annotation.x, annotation.y)).distance(pointer_pos)))
+                .filter(|&(_, distance)| distance < ANNOTATION_GRAB_RADIUS)
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(index, _)| index)
+        });
+
+        if response.drag_started() {
+            self.dragged_annotation = hovered;
+        }
+
+        if let Some(index) = self.dragged_annotation {
+            if let Some(annotation) = self.annotations.get_mut(index) {
+                annotation.x = pointer.x;
+                annotation.y = pointer.y;
+            }
+            if response.drag_released() {
+                self.dragged_annotation = None;
+            }
+        }
+
+        if response.clicked() && hovered.is_none() {
+            self.annotations.push(Annotation { x: pointer.x, y: pointer.y, text: String::new() });
+            self.focus_annotation = Some(self.annotations.len() - 1);
+            self.show_annotations = true;
+        }
+
+        if response.secondary_clicked() {
+            if let Some(index) = hovered {
+                self.annotations.remove(index);
+                self.dragged_annotation = None;
+                self.focus_annotation = None;
+            }
+        }
+    }
+
+    fn annotations_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_annotations;
+        let mut remove_index = None;
+
+        egui::Window::new("Annotations").open(&mut open).show(ctx, |ui| {
+            if self.annotations.is_empty() {
+                ui.label("Turn on Annotate and click the plot to place a label");
+                return;
+            }
+
+            egui::Grid::new("annotations_grid").striped(true).show(ui, |ui| {
+                ui.label("Text");
+                ui.label("x");
+                ui.label("y");
+                ui.end_row();
+
+                for (index, annotation) in self.annotations.iter_mut().enumerate() {
+                    let text_edit = ui.add(egui::TextEdit::singleline(&mut annotation.text).hint_text("label"));
+                    if self.focus_annotation == Some(index) {
+                        text_edit.request_focus();
+                        self.focus_annotation = None;
+                    }
+                    ui.add(egui::DragValue::new(&mut annotation.x).speed(0.1));
+                    ui.add(egui::DragValue::new(&mut annotation.y).speed(0.1));
+                    if ui.small_button("X").on_hover_text("Delete label").clicked() {
+                        remove_index = Some(index);
+                    }
+                    ui.end_row();
+                }
+            });
+
+            if ui.button("Clear All").clicked() {
+                self.annotations.clear();
+            }
+        });
+
+        if let Some(index) = remove_index.filter(|&index| index < self.annotations.len()) {
+            self.annotations.remove(index);
+            self.dragged_annotation = None;
+        }
+
+        self.show_annotations = open;
+    }
+
     
 }
\ No newline at end of file
@@ -0,0 +1,47 @@
+use std::fs::File;
+use std::io::{BufReader, Write};
+
+use serde::{Serialize, Deserialize};
+
+use rfd::FileDialog;
+
+use crate::utils::plot_manager::{Annotation, SavedView};
+use crate::utils::settings::Settings;
+
+// Everything from a session that can be saved to and restored from a YAML file
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct SessionConfig {
+    pub settings: Settings,
+    pub views: Vec<SavedView>,
+    pub annotations: Vec<Annotation>,
+}
+
+impl SessionConfig {
+    pub fn save_to_yaml(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(file_path) = FileDialog::new()
+            .set_file_name("session.yaml")
+            .add_filter("YAML Files", &["yaml", "yml"])
+            .save_file() {
+
+                let serialized = serde_yaml::to_string(self)?;
+                let mut file = File::create(file_path)?;
+                file.write_all(serialized.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Returns None if no file was picked.
+    pub fn load_from_yaml() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        if let Some(file_path) = FileDialog::new()
+            .add_filter("YAML Files", &["yaml", "yml"])
+            .pick_file() {
+
+                let file = File::open(file_path)?;
+                let reader = BufReader::new(file);
+                let session: SessionConfig = serde_yaml::from_reader(reader)?;
+                return Ok(Some(session));
+        }
+        Ok(None)
+    }
+}
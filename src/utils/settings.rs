@@ -0,0 +1,424 @@
+use polars::prelude::*;
+use serde::{Serialize, Deserialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::utils::histogram2d::StorageMode;
+
+// Set by the first apply_thread_count, after which polars may have built its thread pool
+static POLARS_STARTED: AtomicBool = AtomicBool::new(false);
+
+// Time columns that can be given a linear calibration (e.g. channels to ns)
+pub const TIME_COLUMN_NAMES: &[&str] = &[
+    "AnodeFrontTime_AnodeBackTime", "AnodeBackTime_AnodeFrontTime",
+    "AnodeFrontTime_ScintLeftTime", "AnodeBackTime_ScintLeftTime",
+    "DelayFrontLeftTime_ScintLeftTime", "DelayFrontRightTime_ScintLeftTime",
+    "DelayBackLeftTime_ScintLeftTime", "DelayBackRightTime_ScintLeftTime",
+    "ScintRightTime_ScintLeftTime",
+    "DelayFrontLeftTime_AnodeFrontTime", "DelayFrontRightTime_AnodeFrontTime",
+    "DelayBackLeftTime_AnodeFrontTime", "DelayBackRightTime_AnodeFrontTime",
+    "DelayFrontLeftTime_AnodeBackTime", "DelayFrontRightTime_AnodeBackTime",
+    "DelayBackLeftTime_AnodeBackTime", "DelayBackRightTime_AnodeBackTime",
+];
+
+// Energy columns that can be followed over the run
+const ENERGY_COLUMN_NAMES: &[&str] = &[
+    "ScintLeftEnergy", "ScintRightEnergy", "AnodeFrontEnergy", "AnodeBackEnergy", "CathodeEnergy",
+    "DelayFrontLeftEnergy", "DelayFrontRightEnergy", "DelayBackLeftEnergy", "DelayBackRightEnergy",
+];
+
+// How polars parallelizes the parquet reads.
+//
+// Reading is usually I/O-bound on spinning disks and network mounts, where a single reader
+// (or column level parallelism) avoids thrashing the storage. On local NVMe drives splitting
+// the files by row group keeps all of the threads busy and is typically the fastest option.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ScanParallelism {
+    Auto,
+    Columns,
+    RowGroups,
+    None,
+}
+
+impl ScanParallelism {
+    fn to_polars(self) -> ParallelStrategy {
+        match self {
+            ScanParallelism::Auto => ParallelStrategy::Auto,
+            ScanParallelism::Columns => ParallelStrategy::Columns,
+            ScanParallelism::RowGroups => ParallelStrategy::RowGroups,
+            ScanParallelism::None => ParallelStrategy::None,
+        }
+    }
+}
+
+// Anode that gates the timing histograms and that the delay line times are taken relative to
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum AnodeReference {
+    Front,
+    Back,
+}
+
+impl AnodeReference {
+    pub fn column(self) -> &'static str {
+        match self {
+            AnodeReference::Front => "AnodeFrontTime",
+            AnodeReference::Back => "AnodeBackTime",
+        }
+    }
+}
+
+// Which events count as hitting both planes of the focal plane detector
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum BothPlanesDefinition {
+    X1AndX2, // X1 and X2 are both valid
+    Xavg, // Xavg is valid, for data where Xavg is filled directly
+}
+
+// What the delay line average energy is when only one side of the delay line fired
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum DelayAverageMode {
+    Exclude,
+    UseValidSide,
+}
+
+// Linear transform (slope * t + offset) applied to a time column when the histograms are built
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimeCalibration {
+    pub column: String,
+    pub slope: f64,  // e.g. ns per channel
+    pub offset: f64,
+}
+
+// Calibration used for a column: the first one given for it, none if that one has a slope of 0 (it would
+// squeeze every time into one value). The UI doesn't allow either, but a session file can have them.
+pub fn active_time_calibration<'a>(calibrations: &'a [TimeCalibration], column: &str) -> Option<&'a TimeCalibration> {
+    calibrations.iter()
+        .find(|calibration| calibration.column == column)
+        .filter(|calibration| calibration.slope != 0.0)
+}
+
+impl TimeCalibration {
+    fn apply(&self, value: f64) -> f64 {
+        self.slope * value + self.offset
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub parallelism: ScanParallelism,
+    pub num_threads: usize, // 0 lets polars use one thread per core
+    pub time_calibrations: Vec<TimeCalibration>,
+    pub live_recalibration: bool, // keep the raw time columns after loading so calibration changes refill right away
+    pub time_reference_anode: AnodeReference,
+    pub delay_average_mode: DelayAverageMode,
+    pub both_planes: BothPlanesDefinition,
+    pub sentinel: f64, // value marking "no value" in a column
+    pub fill_invalid: bool, // fill the no value markers instead of skipping them
+    pub max_heatmap_bars: usize, // 0 = no limit
+    pub hist2d_storage: StorageMode,
+    pub progression_column: String, // monotonically increasing column, empty uses the event number
+    pub progression_energy_column: String,
+    pub progression_bins: usize,
+    pub snap_ranges: bool, // round the edges of ranges computed from the data
+    pub common_columns_only: bool, // drop columns missing from some of the files instead of leaving them empty
+    pub theta_degrees: bool, // bin Theta in degrees instead of radians
+    pub cebra_min_multiplicity: usize, // CeBrA histograms need at least this many Cebra{n}Energy values, 0 = off
+    pub compute_xavg: bool, // derive Xavg from X1 and X2 when the files don't have it
+    pub xavg_x1_weight: f64, // Xavg = w * X1 + (1 - w) * X2
+    pub check_xavg: bool, // histogram the difference between the Xavg of the files and the computed one
+    pub confirm_file_count: usize, // loading more files than this asks first, 0 = never ask
+    pub sample_fraction: f64, // fraction of the events used to build the histograms, 1 = all
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        Self {
+            parallelism: ScanParallelism::Auto,
+            num_threads: 0,
+            time_calibrations: Vec::new(),
+            live_recalibration: false,
+            time_reference_anode: AnodeReference::Back,
+            delay_average_mode: DelayAverageMode::Exclude,
+            both_planes: BothPlanesDefinition::X1AndX2,
+            sentinel: -1e6,
+            fill_invalid: false,
+            max_heatmap_bars: 100_000,
+            hist2d_storage: StorageMode::Auto,
+            progression_column: String::new(),
+            progression_energy_column: "ScintLeftEnergy".to_string(),
+            progression_bins: 1000,
+            snap_ranges: true,
+            common_columns_only: true,
+            theta_degrees: false,
+            cebra_min_multiplicity: 0,
+            compute_xavg: true,
+            xavg_x1_weight: 0.5,
+            check_xavg: true,
+            confirm_file_count: 200,
+            sample_fraction: 1.0,
+        }
+    }
+
+    fn time_calibration(&self, column: &str) -> Option<&TimeCalibration> {
+        active_time_calibration(&self.time_calibrations, column)
+    }
+
+    // A column in calibrated units, the column itself if it isn't calibrated. The no value marker is kept as is.
+    pub fn calibrated_column(&self, column: &str) -> Expr {
+        match self.time_calibration(column) {
+            Some(calibration) => when(col(column).neq(lit(self.sentinel)))
+                .then(col(column) * lit(calibration.slope) + lit(calibration.offset))
+                .otherwise(col(column)),
+            None => col(column),
+        }
+    }
+
+    // Expressions that replace the calibrated time columns with their transformed values, one per column.
+    pub fn time_calibration_expressions(&self) -> Vec<Expr> {
+        TIME_COLUMN_NAMES.iter()
+            .filter(|&&column| self.time_calibration(column).is_some())
+            .map(|&column| self.calibrated_column(column).alias(column))
+            .collect()
+    }
+
+    // Histogram range for a time column, transformed into calibrated units if the column has a calibration.
+    pub fn time_range(&self, column: &str, range: (f64, f64)) -> (f64, f64) {
+        match self.time_calibration(column) {
+            Some(calibration) => {
+                let (start, end) = (calibration.apply(range.0), calibration.apply(range.1));
+                (start.min(end), start.max(end))
+            }
+            None => range,
+        }
+    }
+
+    // Scan arguments used everywhere a parquet file is read.
+    pub fn scan_args(&self) -> ScanArgsParquet {
+        ScanArgsParquet {
+            parallel: self.parallelism.to_polars(),
+            ..Default::default()
+        }
+    }
+
+    // Polars builds its thread pool once, the first time it is used, from POLARS_MAX_THREADS.
+    // Called before every use of polars (loads, cut saves), only the first call sets the count.
+    pub fn apply_thread_count(&self) {
+        if !POLARS_STARTED.swap(true, Ordering::Relaxed) && self.num_threads > 0 {
+            std::env::set_var("POLARS_MAX_THREADS", self.num_threads.to_string());
+        }
+    }
+
+    // True once the thread count can't be changed anymore
+    pub fn polars_started() -> bool {
+        POLARS_STARTED.load(Ordering::Relaxed)
+    }
+
+    // UI for the settings. The thread count can only be changed until polars has started its thread pool.
+    pub fn settings_ui(&mut self, ui: &mut egui::Ui, threads_locked: bool) {
+        ui.label("Parquet Parallelism")
+            .on_hover_text("How polars splits the work when reading the parquet files.\nRow Groups is usually fastest on local SSD/NVMe storage.\nColumns or None reduce contention on spinning disks and network mounts where reading is I/O-bound.");
+
+        ui.horizontal_wrapped(|ui| {
+            ui.radio_value(&mut self.parallelism, ScanParallelism::Auto, "Auto");
+            ui.radio_value(&mut self.parallelism, ScanParallelism::Columns, "Columns");
+            ui.radio_value(&mut self.parallelism, ScanParallelism::RowGroups, "Row Groups");
+            ui.radio_value(&mut self.parallelism, ScanParallelism::None, "None");
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Threads: ");
+            ui.add_enabled(!threads_locked, egui::DragValue::new(&mut self.num_threads).speed(0.1).clamp_range(0..=256))
+                .on_hover_text("Size of the polars thread pool (0 = one per core).\nMore threads help on fast storage, fewer threads help when reading is I/O-bound.")
+                .on_disabled_hover_text("The polars thread pool is created on the first load and can't be resized until the program is restarted.\nSet POLARS_MAX_THREADS before launching to change it.");
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Confirm Loads Over: ");
+            ui.add(egui::DragValue::new(&mut self.confirm_file_count).speed(1.0).clamp_range(0..=100_000).suffix(" files"))
+                .on_hover_text("Ask before building histograms from more files than this, e.g. after Select All on a large directory (0 = never ask).");
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Sample Fraction: ");
+            ui.add(egui::DragValue::new(&mut self.sample_fraction).speed(0.01).clamp_range(0.001..=1.0))
+                .on_hover_text("Build the histograms from this fraction of the events, spread evenly over the files, for a quick look (1 = every event).\nThe same events are picked on every load, so binning and cuts can be compared. Set back to 1 for the final build.");
+        });
+
+        ui.separator();
+
+        ui.label("2D Histogram Storage")
+            .on_hover_text("Sparse storage (hash map) has no cost for empty bins, dense storage (flat array) is faster for well filled histograms.\nAuto starts sparse and switches to dense once a quarter of the bins are filled.");
+
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.hist2d_storage, StorageMode::Auto, "Auto");
+            ui.radio_value(&mut self.hist2d_storage, StorageMode::Sparse, "Sparse");
+            ui.radio_value(&mut self.hist2d_storage, StorageMode::Dense, "Dense");
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Max Heatmap Bars: ");
+            ui.add(egui::DragValue::new(&mut self.max_heatmap_bars).speed(1000.0).clamp_range(0..=10_000_000))
+                .on_hover_text("Each occupied 2D bin is drawn as a bar. Heatmaps with more occupied bins are drawn with neighbouring bins merged (0 = no limit).\nThe histogram itself is not changed.");
+        });
+
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            ui.label("No Value Marker: ");
+            ui.add(egui::DragValue::new(&mut self.sentinel).speed(1.0))
+                .on_hover_text("Value written in a column when the detector didn't fire (-1e6 by default).\nThese values are skipped when filling histograms and are never inside a cut.");
+        });
+
+        ui.checkbox(&mut self.fill_invalid, "Show No Value Events")
+            .on_hover_text("Fill the no value markers into the histograms instead of skipping them, to check how often a detector didn't fire.\nThey usually land outside the range and show up as underflow in the legend and the Info window.");
+
+        ui.separator();
+
+        ui.checkbox(&mut self.snap_ranges, "Round Data Ranges")
+            .on_hover_text("Snap ranges taken from the data to round numbers (1-2-5 bin widths).\nThe number of bins is adjusted slightly so the bin edges are readable.");
+
+        ui.checkbox(&mut self.common_columns_only, "Common Columns Only")
+            .on_hover_text("When the selected files don't all have the same columns, only read the columns found in every file.\nHistograms that need a missing column are skipped and listed in the load report.\nWhen off, the missing columns are empty for the events of the files without them.");
+
+        ui.checkbox(&mut self.theta_degrees, "Theta in Degrees")
+            .on_hover_text("Bin Theta from 0 to 90 degrees instead of 0 to pi/2 radians.");
+
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.compute_xavg, "Compute Missing Xavg")
+                .on_hover_text("For files without an Xavg column, compute it from X1 and X2 for events where both planes have a value.\nXavg = w * X1 + (1 - w) * X2. The weight depends on the focal plane position of the reaction.");
+            ui.add_enabled(self.compute_xavg || self.check_xavg, egui::DragValue::new(&mut self.xavg_x1_weight).speed(0.01).clamp_range(0.0..=1.0).prefix("w: "));
+        });
+
+        ui.checkbox(&mut self.check_xavg, "Check Xavg")
+            .on_hover_text("For files with Xavg, X1 and X2, histogram the Xavg of the files minus w * X1 + (1 - w) * X2 for events where both planes have a value.\nIt peaks sharply at zero when w matches the weighting of the DAQ.");
+
+        ui.separator();
+
+        ui.label("Both Planes Events")
+            .on_hover_text("Events used for the bothplanes histograms.\nUse Xavg if your data has a valid Xavg for events where X1 or X2 isn't filled.");
+
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.both_planes, BothPlanesDefinition::X1AndX2, "X1 and X2 Valid");
+            ui.radio_value(&mut self.both_planes, BothPlanesDefinition::Xavg, "Xavg Valid");
+        });
+
+        ui.separator();
+
+        ui.label("Delay Average With One Side")
+            .on_hover_text("How the DelayFront/BackAverageEnergy is computed when only the left or right side of the delay line fired");
+
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.delay_average_mode, DelayAverageMode::Exclude, "Exclude Event");
+            ui.radio_value(&mut self.delay_average_mode, DelayAverageMode::UseValidSide, "Use Valid Side");
+        });
+
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            ui.label("Min CeBrA Multiplicity: ");
+            ui.add(egui::DragValue::new(&mut self.cebra_min_multiplicity).speed(0.1).clamp_range(0..=64))
+                .on_hover_text("Only fill the CeBrA histograms with events where at least this many CeBrA detectors fired (Cebra{n}Energy columns with a value).\nThe multiplicity histogram and the SPS histograms keep every event. 0 uses every event.");
+        });
+
+        ui.separator();
+
+        ui.label("Run Progression")
+            .on_hover_text("2D histogram of an energy vs a timestamp column (or the event number) to monitor gain drift over a run");
+
+        ui.horizontal(|ui| {
+            ui.label("Time Column: ");
+            ui.add(egui::TextEdit::singleline(&mut self.progression_column).hint_text("event number"));
+        });
+
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_label("Energy")
+                .selected_text(&self.progression_energy_column)
+                .show_ui(ui, |ui| {
+                    for &column in ENERGY_COLUMN_NAMES.iter() {
+                        ui.selectable_value(&mut self.progression_energy_column, column.to_string(), column);
+                    }
+                });
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Bins: ");
+            ui.add(egui::DragValue::new(&mut self.progression_bins).speed(10).clamp_range(1..=100000));
+        });
+
+        ui.separator();
+
+        ui.label("Time Reference Anode")
+            .on_hover_text("Anode that must have fired for an event to be in the timing histograms.\nThe delay line times are also histogrammed relative to it.\nUse the front anode when the back anode is noisy.");
+
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.time_reference_anode, AnodeReference::Back, "Back");
+            ui.radio_value(&mut self.time_reference_anode, AnodeReference::Front, "Front");
+        });
+
+        ui.separator();
+
+        ui.label("Time Calibrations")
+            .on_hover_text("Linear transform (slope * t + offset) applied to a time column when the histograms are built, e.g. to display times in ns.\nThe histogram range is transformed as well.");
+
+        // A column can only have one calibration
+        let used_columns: Vec<String> = self.time_calibrations.iter().map(|calibration| calibration.column.clone()).collect();
+
+        let mut remove_index = None;
+        for (index, calibration) in self.time_calibrations.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_id_source(("time_calibration", index))
+                    .selected_text(&calibration.column)
+                    .show_ui(ui, |ui| {
+                        for &column in TIME_COLUMN_NAMES.iter() {
+                            let used_elsewhere = used_columns.iter().enumerate().any(|(other, used)| other != index && used == column);
+                            ui.add_enabled_ui(!used_elsewhere, |ui| {
+                                ui.selectable_value(&mut calibration.column, column.to_string(), column);
+                            });
+                        }
+                    });
+
+                if ui.button("X").on_hover_text("Remove calibration").clicked() {
+                    remove_index = Some(index);
+                }
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Slope: ");
+                let previous_slope = calibration.slope;
+                ui.add(egui::DragValue::new(&mut calibration.slope).speed(0.001))
+                    .on_hover_text("Can't be 0, every time would land in the same bin");
+                if calibration.slope == 0.0 {
+                    calibration.slope = if previous_slope != 0.0 { previous_slope } else { 1.0 };
+                }
+                ui.label("Offset: ");
+                ui.add(egui::DragValue::new(&mut calibration.offset).speed(0.1));
+            });
+        }
+
+        if let Some(index) = remove_index {
+            self.time_calibrations.remove(index);
+        }
+
+        ui.checkbox(&mut self.live_recalibration, "Live Recalibration")
+            .on_hover_text("Keep the raw time columns in memory after loading, so changing a calibration refills the time histograms right away without reading the files again.\nTakes effect from the next load. Uses more memory and the load reads the time columns one extra time.\nPer file histograms keep the calibration they were loaded with.");
+
+        let unused_column = TIME_COLUMN_NAMES.iter().find(|&&column| !used_columns.iter().any(|used| used == column));
+        if ui.add_enabled(unused_column.is_some(), egui::Button::new("Add Time Calibration")).clicked() {
+            if let Some(&column) = unused_column {
+                self.time_calibrations.push(TimeCalibration {
+                    column: column.to_string(),
+                    slope: 1.0,
+                    offset: 0.0,
+                });
+            }
+        }
+    }
+}
@@ -1,142 +1,619 @@
 use eframe::egui::{self};
 use std::sync::Arc;
-use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Path, PathBuf};
 use std::fs::{self};
-use std::time::SystemTime;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime};
+
+use polars::prelude::PolarsError;
 
 use crate::utils::cut::CutHandler;
 
 use super::plot_manager::PlotManager;
 
-use crate::histograms::histogram_creation::add_histograms;
-use crate::utils::histogrammer::Histogrammer;
+use crate::histograms::histogram_creation::{add_histograms, recalibrate, refill_histograms};
+use crate::utils::histogrammer::{FillProgress, Histogrammer};
+use crate::utils::settings::{Settings, TimeCalibration};
+use crate::utils::session::SessionConfig;
+
+// How often the file list is re-read so new runs show up
+const DIRECTORY_REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+// How long the time calibrations must stay the same before a live recalibration, so dragging a value
+// refills the histograms once at the end instead of every frame
+const RECALIBRATION_DELAY: Duration = Duration::from_millis(300);
+
+// Function to get the modification time of a file
+fn get_modification_time(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).ok().and_then(|metadata| metadata.modified().ok())
+}
+
+// Messages from the thread building the histograms
+enum LoadMessage {
+    Progress(FillProgress),
+    Finished(Box<Result<Histogrammer, PolarsError>>), // boxed, the Histogrammer is much larger than a progress update
+}
+
+// A histogram build running in the background. The worker owns the Histogrammer it fills and sends it
+// back when done, the UI never shares one with it (see PlotManager::replace_histogrammer).
+struct BackgroundLoad {
+    receiver: Receiver<LoadMessage>,
+    started: Instant,
+    progress: Option<FillProgress>,
+    cuts: Option<CutHandler>, // cuts the events must be inside
+    select_per_file: Option<Vec<String>>, // histograms whose per file copies are shown once loaded
+    refill: Option<Vec<String>>, // histograms refilled with the active cut, put in place of the loaded ones
+    file_paths: Arc<[PathBuf]>, // files the histograms are built from
+    cancel: Arc<AtomicBool>, // checked by the worker between chunks
+}
+
+// How entries in the file list are labelled. Selection always uses the full path.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FileNameDisplay {
+    Stem,         // file name without .parquet
+    RelativePath, // relative to the selected directory
+    FullPath,
+}
+
+impl FileNameDisplay {
+    fn label(self, path: &Path, directory: Option<&Path>) -> String {
+        match self {
+            FileNameDisplay::Stem => path.file_stem().and_then(|s| s.to_str()).unwrap_or_default().to_string(),
+            FileNameDisplay::RelativePath => directory
+                .and_then(|directory| path.strip_prefix(directory).ok())
+                .unwrap_or(path)
+                .display()
+                .to_string(),
+            FileNameDisplay::FullPath => path.display().to_string(),
+        }
+    }
+}
+
+// A load waiting for confirmation because of the file count
+struct PendingLoad {
+    apply_cuts: bool,
+    per_file: Vec<String>, // see load_histograms
+    total_bytes: u64, // size of the selected files on disk
+}
 
 pub struct MyApp {
     selected_directory: Option<PathBuf>,
+    directory_files: Vec<PathBuf>,
+    modification_times: HashMap<PathBuf, SystemTime>, // of directory_files, read when the directory is refreshed
+    listed_files: Vec<PathBuf>, // directory_files that pass the modification time window, see update_listed_files
+    listed_set: HashSet<PathBuf>, // same as listed_files, to look paths up
+    directory_error: Option<String>,
+    last_directory_refresh: Option<Instant>,
     file_paths: Vec<PathBuf>,
     select_all: bool,
     histograms_loaded: bool,
+    cuts_applied: bool,
+    loaded_file_paths: Arc<[PathBuf]>, // files of the histograms on screen, the selection may have changed since
+    loaded_cuts: Option<CutHandler>, // cuts the histograms on screen were built with, as they were then
     plot_manager: PlotManager,
+    settings: Settings,
+    background_load: Option<BackgroundLoad>,
+    file_name_display: FileNameDisplay,
+    modified_filter: bool, // only list files modified within modified_hours
+    modified_hours: (f64, f64), // newest and oldest end of the window, in hours before now
+    pending_load: Option<PendingLoad>,
+    seen_calibrations: Vec<TimeCalibration>, // time calibrations of the last frame, to notice changes
+    calibrations_changed: Option<Instant>, // last change not yet applied by a live recalibration
 }
 
 impl MyApp {
     pub fn new() -> Self {
         Self {
             selected_directory: None, 
+            directory_files: Vec::new(),
+            modification_times: HashMap::new(),
+            listed_files: Vec::new(),
+            listed_set: HashSet::new(),
+            directory_error: None,
+            last_directory_refresh: None,
             file_paths: Vec::new(),
             select_all: false,
             histograms_loaded: false,
+            cuts_applied: false,
+            loaded_file_paths: Arc::from(Vec::new()),
+            loaded_cuts: None,
             plot_manager: PlotManager::new(Histogrammer::new(), CutHandler::new()),
+            settings: Settings::new(),
+            background_load: None,
+            file_name_display: FileNameDisplay::Stem,
+            modified_filter: false,
+            modified_hours: (0.0, 12.0),
+            pending_load: None,
+            seen_calibrations: Vec::new(),
+            calibrations_changed: None,
+        }
+    }
+
+    // Lists the files of the directory that pass the modification time window, newest first. Called when the
+    // directory is refreshed or the window changes, not every frame.
+    fn update_listed_files(&mut self) {
+        let now = SystemTime::now();
+        let (newest, oldest) = self.modified_hours;
+        self.listed_files = self.directory_files.iter()
+            .filter(|path| {
+                !self.modified_filter || self.modification_times.get(*path)
+                    .and_then(|&time| now.duration_since(time).ok())
+                    .is_some_and(|age| {
+                        let hours = age.as_secs_f64() / 3600.0;
+                        hours >= newest && hours <= oldest
+                    })
+            })
+            .cloned()
+            .collect();
+        self.listed_set = self.listed_files.iter().cloned().collect();
+    }
+
+    // Loads the histograms (see load_histograms), or asks first when more files are selected than
+    // settings.confirm_file_count. Requests made while a load is waiting for confirmation are ignored.
+    fn request_load(&mut self, apply_cuts: bool, per_file: Vec<String>) {
+        if self.pending_load.is_some() {
+            return;
+        }
+
+        if self.settings.confirm_file_count > 0 && self.file_paths.len() > self.settings.confirm_file_count {
+            let total_bytes = self.file_paths.iter()
+                .filter_map(|path| fs::metadata(path).ok())
+                .map(|metadata| metadata.len())
+                .sum();
+            self.pending_load = Some(PendingLoad { apply_cuts, per_file, total_bytes });
+        } else {
+            self.load_histograms(apply_cuts, per_file);
+        }
+    }
+
+    // Confirmation for a load of many files, with their total size on disk
+    fn confirm_load_window(&mut self, ctx: &egui::Context) {
+        let Some(pending) = &self.pending_load else {
+            return;
+        };
+        let (total_bytes, per_file) = (pending.total_bytes, !pending.per_file.is_empty());
+
+        let mut decision = None;
+        egui::Window::new("Load Many Files?")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(format!("{} files are selected ({:.1} GB on disk).", self.file_paths.len(), total_bytes as f64 / 1e9));
+                ui.label("Building histograms from all of them can take a long time and a lot of memory.");
+                if per_file {
+                    ui.label("The selected histograms are also built from each file on its own, which reads every file again.");
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("Load").clicked() {
+                        decision = Some(true);
+                    }
+                    if ui.button("Cancel").clicked() {
+                        decision = Some(false);
+                    }
+                });
+            });
+
+        match decision {
+            Some(true) => {
+                if let Some(pending) = self.pending_load.take() {
+                    self.load_histograms(pending.apply_cuts, pending.per_file);
+                }
+            }
+            Some(false) => self.pending_load = None,
+            None => {}
+        }
+    }
+
+    // Starts building the histograms from the selected files in the background, optionally keeping only
+    // the events inside the cuts. The histograms in per_file are also built from each file and shown once
+    // loaded. The current histograms stay on screen until the new ones are done.
+    fn load_histograms(&mut self, apply_cuts: bool, per_file: Vec<String>) {
+        // Convert Vec<PathBuf> to Arc<[PathBuf]>
+        let paths_arc: Arc<[PathBuf]> = Arc::from(self.file_paths.clone().into_iter().collect::<Box<[_]>>());
+
+        let cuts = if apply_cuts { Some(self.plot_manager.cutter.clone()) } else { None };
+        let worker_paths = paths_arc.clone();
+        let worker_cuts = cuts.clone();
+        let worker_per_file = per_file.clone();
+        let (receiver, cancel) = self.spawn_build(move |settings, progress, cancel| {
+            add_histograms(worker_paths, settings, worker_cuts.as_ref(), &worker_per_file, progress, cancel)
+        });
+
+        self.background_load = Some(BackgroundLoad {
+            receiver,
+            started: Instant::now(),
+            progress: None,
+            cuts,
+            select_per_file: (!per_file.is_empty()).then_some(per_file),
+            refill: None,
+            file_paths: paths_arc,
+            cancel,
+        });
+    }
+
+    // Starts refilling the histograms in names from the loaded files in the background, with only the events
+    // inside the active cut. The other histograms are kept as they are.
+    fn refill_with_cut(&mut self, names: Vec<String>) {
+        let Some(cut) = self.plot_manager.cutter.active_cut().cloned() else {
+            self.plot_manager.histogrammer.load_report.push("Refill with the cut: no cut is active".to_string());
+            return;
+        };
+
+        let mut refill_cut = CutHandler::new();
+        refill_cut.cuts.insert("refill".to_string(), cut);
+        let paths = self.loaded_file_paths.clone();
+        let loaded_cuts = self.loaded_cuts.clone();
+        let worker_names = names.clone();
+        let (receiver, cancel) = self.spawn_build(move |settings, progress, cancel| {
+            refill_histograms(paths, settings, loaded_cuts.as_ref(), &refill_cut, &worker_names, progress, cancel)
+        });
+
+        self.background_load = Some(BackgroundLoad {
+            receiver,
+            started: Instant::now(),
+            progress: None,
+            cuts: self.loaded_cuts.clone(),
+            select_per_file: None,
+            refill: Some(names),
+            file_paths: self.loaded_file_paths.clone(),
+            cancel,
+        });
+    }
+
+    // Runs build on a new thread with a copy of the settings, sending its progress and result back
+    fn spawn_build<F>(&mut self, build: F) -> (Receiver<LoadMessage>, Arc<AtomicBool>)
+    where
+        F: FnOnce(&Settings, &dyn Fn(FillProgress), &AtomicBool) -> Result<Histogrammer, PolarsError> + Send + 'static,
+    {
+        // polars creates its thread pool on first use, so the thread count must be set before the first scan
+        self.settings.apply_thread_count();
+
+        let settings = self.settings.clone();
+        let (sender, receiver) = mpsc::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let worker_cancel = cancel.clone();
+
+        std::thread::spawn(move || {
+            let progress_sender = sender.clone();
+            let result = build(&settings, &|progress| {
+                let _ = progress_sender.send(LoadMessage::Progress(progress));
+            }, &worker_cancel);
+            let _ = sender.send(LoadMessage::Finished(Box::new(result)));
+        });
+
+        (receiver, cancel)
+    }
+
+    // Picks up the progress and result of the background load.
+    fn poll_background_load(&mut self) {
+        let Some(load) = &mut self.background_load else {
+            return;
+        };
+
+        loop {
+            match load.receiver.try_recv() {
+                Ok(LoadMessage::Progress(progress)) => {
+                    if !progress.reading {
+                        self.plot_manager.live_counts = progress.histogram_counts.iter().cloned().collect();
+                    }
+                    load.progress = Some(progress);
+                }
+                Ok(LoadMessage::Finished(result)) => {
+                    self.plot_manager.live_counts.clear();
+                    match *result {
+                        Ok(histogrammer) if load.refill.is_some() => {
+                            let names = load.refill.take().unwrap_or_default();
+                            self.plot_manager.histogrammer.replace_refilled(histogrammer, &names);
+                        }
+                        Err(e) if load.refill.is_some() && !load.cancel.load(Ordering::Relaxed) => {
+                            self.plot_manager.histogrammer.load_report.push(format!("Failed to refill with the cut: {}", e));
+                        }
+                        Ok(histogrammer) => {
+                            // Runs before anything is drawn this frame, so the swap is atomic for the UI
+                            self.plot_manager.replace_histogrammer(histogrammer);
+                            self.histograms_loaded = true;
+                            self.cuts_applied = load.cuts.is_some();
+                            self.loaded_file_paths = load.file_paths.clone();
+                            self.loaded_cuts = load.cuts.take();
+                            if let Some(names) = load.select_per_file.take() {
+                                self.plot_manager.select_per_file_histograms(&names);
+                            }
+                        }
+                        // The previous histograms are kept
+                        Err(_) if load.cancel.load(Ordering::Relaxed) => {
+                            eprintln!("Histogram build cancelled");
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to load histograms: {:?}", e);
+                        }
+                    }
+                    self.background_load = None;
+                    return;
+                }
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    eprintln!("Failed to load histograms: the loading thread stopped unexpectedly");
+                    self.plot_manager.live_counts.clear();
+                    self.background_load = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    // Writes the definitions of the built histograms, as JSON if the file name ends in .json and YAML otherwise
+    fn export_manifest(&self) {
+        let Some(path) = rfd::FileDialog::new()
+            .set_file_name("histograms.yaml")
+            .add_filter("YAML Files", &["yaml", "yml"])
+            .add_filter("JSON Files", &["json"])
+            .save_file() else {
+            return;
+        };
+
+        let manifest = self.plot_manager.histogrammer.manifest();
+        let serialized = if path.extension().and_then(|s| s.to_str()) == Some("json") {
+            serde_json::to_string_pretty(&manifest).map_err(|e| e.to_string())
+        } else {
+            serde_yaml::to_string(&manifest).map_err(|e| e.to_string())
+        };
+
+        if let Err(e) = serialized.and_then(|text| fs::write(&path, text).map_err(|e| e.to_string())) {
+            eprintln!("Failed to export the manifest to {:?}: {}", path, e);
+        }
+    }
+
+    // Progress bar and throughput of the background load
+    fn background_load_ui(&self, ui: &mut egui::Ui) {
+        let Some(load) = &self.background_load else {
+            return;
+        };
+
+        match &load.progress {
+            Some(progress) if !progress.reading => {
+                let fraction = progress.events_filled as f32 / progress.total_events.max(1) as f32;
+                ui.add(egui::ProgressBar::new(fraction).text(format!("{} / {} events", progress.events_filled, progress.total_events)));
+
+                // Over the whole load, reading the files included
+                let elapsed = load.started.elapsed().as_secs_f64();
+                if elapsed > 0.0 {
+                    ui.label(format!("{:.0} events/s", progress.events_filled as f64 / elapsed))
+                        .on_hover_text("Events filled per second since the load started, reading the files included");
+                }
+            }
+            _ => {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label("Reading files...");
+                });
+            }
+        }
+
+        if load.cancel.load(Ordering::Relaxed) {
+            ui.label("Cancelling...")
+                .on_hover_text("The build stops at the end of the current step. Reading the files can't be interrupted.");
+        } else if ui.button("Cancel").on_hover_text("Stop the build and keep the current histograms").clicked() {
+            load.cancel.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Re-reads the parquet files in the selected directory. If the directory can't be read (e.g. a network
+    // mount dropped) the previous listing and the selected files are kept so the outage can be retried.
+    fn refresh_directory(&mut self) {
+        self.last_directory_refresh = Some(Instant::now());
+
+        let Some(dir) = &self.selected_directory else {
+            return;
+        };
+
+        match fs::read_dir(dir) {
+            Ok(entries) => {
+                let mut files: Vec<PathBuf> = entries
+                    .filter_map(Result::ok)
+                    .map(|entry| entry.path())
+                    .filter(|path| path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("parquet"))
+                    .collect();
+
+                // Sort files by modification time, read once per file
+                self.modification_times = files.iter()
+                    .filter_map(|path| Some((path.clone(), get_modification_time(path)?)))
+                    .collect();
+                files.sort_by_key(|path| std::cmp::Reverse(self.modification_times.get(path).copied().unwrap_or(SystemTime::UNIX_EPOCH)));
+
+                self.directory_files = files;
+                self.directory_error = None;
+            }
+            Err(e) => {
+                self.directory_error = Some(e.to_string());
+            }
         }
+
+        self.update_listed_files();
     }
 
 }
 
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.poll_background_load();
+        self.plot_manager.histogrammer.max_heatmap_bars = self.settings.max_heatmap_bars;
+        if self.background_load.is_some() {
+            ctx.request_repaint_after(Duration::from_millis(100));
+        }
+
         // egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
         egui::SidePanel::left("files").show(ctx, |ui| {
 
             if ui.button("Open Directory").clicked() {
                 if let Some(path) = rfd::FileDialog::new().pick_folder() {
                     self.selected_directory = Some(path);
+                    self.directory_files.clear();
+                    self.refresh_directory();
                 }
             }
 
             ui.separator();
 
-            // Function to get the modification time of a file
-            fn get_modification_time(path: &PathBuf) -> Option<SystemTime> {
-                fs::metadata(path).ok().and_then(|metadata| metadata.modified().ok())
-            }
+            egui::CollapsingHeader::new("Settings").show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("Save Session").clicked() {
+                        let session = SessionConfig {
+                            settings: self.settings.clone(),
+                            views: self.plot_manager.views.clone(),
+                            annotations: self.plot_manager.annotations.clone(),
+                        };
+                        if let Err(e) = session.save_to_yaml() {
+                            eprintln!("Error saving session: {:?}", e);
+                        }
+                    }
+
+                    if ui.button("Load Session").clicked() {
+                        match SessionConfig::load_from_yaml() {
+                            Ok(Some(session)) => {
+                                self.settings = session.settings;
+                                self.plot_manager.views = session.views;
+                                self.plot_manager.annotations = session.annotations;
+                            }
+                            Ok(None) => {}
+                            Err(e) => eprintln!("Error loading session: {:?}", e),
+                        }
+                    }
+                });
+
+                ui.separator();
+
+                self.settings.settings_ui(ui, Settings::polars_started());
+            });
 
-            if let Some(dir) = &self.selected_directory {
+            ui.separator();
+
+            if self.selected_directory.is_some() {
 
                 ui.separator();
 
-                if ui.button("Load Histograms").clicked() {
+                let has_selection = !self.file_paths.is_empty();
+                let loading = self.background_load.is_some() || self.pending_load.is_some();
+
+                if ui.add_enabled(has_selection && !loading, egui::Button::new("Load Histograms"))
+                    .on_disabled_hover_text(if loading { "Histograms are being built" } else { "Select at least one parquet file" })
+                    .clicked() {
                     
-                    self.histograms_loaded = false;
+                    self.request_load(false, Vec::new());
+                }
+
+                self.background_load_ui(ui);
 
-                    if !self.file_paths.is_empty() {
-                        // Convert Vec<PathBuf> to Arc<[PathBuf]>
-                        let paths_arc: Arc<[PathBuf]> = Arc::from(self.file_paths.clone().into_iter().collect::<Box<[_]>>());
+                if !has_selection {
+                    ui.colored_label(ui.visuals().warn_fg_color, "Select at least one parquet file");
+                }
 
-                        match add_histograms(paths_arc.clone()) {
+                if self.histograms_loaded && self.cuts_applied {
+                    ui.label("Histograms are gated by the cuts");
+                }
 
-                            Ok(histogrammer) => {
-                                // self.histogrammer = histogrammer;
-                                self.plot_manager.histogrammer = histogrammer;
-                                self.histograms_loaded = true;
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to load histograms: {:?}", e);
-                            }
+                let sample_fraction = self.plot_manager.histogrammer.sample_fraction;
+                if self.histograms_loaded && sample_fraction < 1.0 {
+                    ui.colored_label(ui.visuals().warn_fg_color, format!("SAMPLED: histograms use {:.1}% of the events", sample_fraction * 100.0))
+                        .on_hover_text("Set the sample fraction back to 1 in the settings and reload for the full result");
+                }
+
+                if self.histograms_loaded && ui.button("Export Manifest")
+                    .on_hover_text("Save the name, type, binning, source columns and counts of every histogram as YAML or JSON")
+                    .clicked() {
+                    self.export_manifest();
+                }
+
+                let load_report = &self.plot_manager.histogrammer.load_report;
+                if self.histograms_loaded && !load_report.is_empty() {
+                    egui::CollapsingHeader::new(format!("Load Report ({})", load_report.len())).show(ui, |ui| {
+                        for line in load_report.iter() {
+                            ui.colored_label(ui.visuals().warn_fg_color, line);
                         }
-                    }
+                    });
                 }
 
                 ui.separator();
 
                 ui.label("Files in directory");
 
+                // Keep the list up to date while the directory is reachable. After a failure it is only re-read on retry.
+                let refresh_due = self.last_directory_refresh.is_none_or(|time| time.elapsed() >= DIRECTORY_REFRESH_INTERVAL);
+                if self.directory_error.is_none() && refresh_due {
+                    self.refresh_directory();
+                }
+
+                if let Some(error) = self.directory_error.clone() {
+                    ui.colored_label(ui.visuals().error_fg_color, format!("Failed to read directory: {}", error))
+                        .on_hover_text("Your selected files are kept. The list below is from the last successful read.");
+                    if ui.button("Retry").clicked() {
+                        self.refresh_directory();
+                    }
+                }
+
+                let window_changed = ui.horizontal(|ui| {
+                    let mut changed = ui.checkbox(&mut self.modified_filter, "Modified between")
+                        .on_hover_text("Only list files modified in this window, e.g. the runs of the current shift")
+                        .changed();
+                    changed |= ui.add_enabled(self.modified_filter, egui::DragValue::new(&mut self.modified_hours.0).speed(0.1).clamp_range(0.0..=self.modified_hours.1)).changed();
+                    ui.label("and");
+                    changed |= ui.add_enabled(self.modified_filter, egui::DragValue::new(&mut self.modified_hours.1).speed(0.1).clamp_range(self.modified_hours.0..=100_000.0)).changed();
+                    ui.label("hours ago");
+                    changed
+                }).inner;
+                if window_changed {
+                    self.update_listed_files();
+                }
+
+                if self.modified_filter {
+                    let hidden_selected = self.file_paths.iter().filter(|path| !self.listed_set.contains(*path)).count();
+                    ui.label(format!("{} of {} files listed", self.listed_files.len(), self.directory_files.len()));
+                    if hidden_selected > 0 {
+                        ui.colored_label(ui.visuals().warn_fg_color, format!("{} selected files are outside the window", hidden_selected))
+                            .on_hover_text("They stay selected and are loaded. Deselect All clears them.");
+                    }
+                }
+
                 if ui.button(if self.select_all { "Deselect All" } else { "Select All" }).clicked() {
                     if self.select_all {
                         // Deselect all files
                         self.file_paths.clear();
                     } else {
-                        // Select all files
-                        if let Ok(entries) = fs::read_dir(dir) {
-                            for entry in entries.filter_map(Result::ok) {
-                                let path = entry.path();
-                                if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("parquet") {
-                                    if !self.file_paths.contains(&path) {
-                                        self.file_paths.push(path);
-                                    }
-                                }
-                            }
-                        }
+                        // Select all listed files
+                        let selected: HashSet<PathBuf> = self.file_paths.iter().cloned().collect();
+                        self.file_paths.extend(self.listed_files.iter().filter(|path| !selected.contains(*path)).cloned());
                     }
                     // Toggle the state
                     self.select_all = !self.select_all;
                 }
                 
+                ui.horizontal(|ui| {
+                    ui.label("Show: ");
+                    ui.radio_value(&mut self.file_name_display, FileNameDisplay::Stem, "Name");
+                    ui.radio_value(&mut self.file_name_display, FileNameDisplay::RelativePath, "Relative Path");
+                    ui.radio_value(&mut self.file_name_display, FileNameDisplay::FullPath, "Full Path");
+                });
+
                 egui::ScrollArea::vertical().show(ui, |ui| {
-                    // Attempt to read the directory
-                    match fs::read_dir(dir) {
-                        Ok(entries) => {
-                            let mut files: Vec<_> = entries
-                                .filter_map(Result::ok)
-                                .filter(|entry| {
-                                    entry.path().is_file() && entry.path().extension().and_then(|s| s.to_str()) == Some("parquet")
-                                })
-                                .collect();
-            
-                            // Sort files by modification time
-                            files.sort_by(|a, b| {
-                                let a_time = get_modification_time(&a.path()).unwrap_or(SystemTime::UNIX_EPOCH);
-                                let b_time = get_modification_time(&b.path()).unwrap_or(SystemTime::UNIX_EPOCH);
-                                b_time.cmp(&a_time) // Sorting in reverse order
-                            });
-            
-                            // Display the files
-                            for entry in files {
-                                let path = entry.path();
-                                if let Some(file_name) = path.file_name().and_then(|s| s.to_str()) {
-                                    let file_name_display = file_name.strip_suffix(".parquet").unwrap_or(file_name);
-                                    if ui.selectable_label(self.file_paths.contains(&path), file_name_display).clicked() {
-                                        if self.file_paths.contains(&path) {
-                                            self.file_paths.retain(|p| p != &path);
-                                        } else {
-                                            self.file_paths.push(path);
-                                        }
-                                    }
-                                }
-                            }
+                    // Display the files
+                    let selected: HashSet<&PathBuf> = self.file_paths.iter().collect();
+                    let mut clicked = None;
+                    for path in self.listed_files.iter() {
+                        let file_name_display = self.file_name_display.label(path, self.selected_directory.as_deref());
+                        if ui.selectable_label(selected.contains(path), file_name_display)
+                            .on_hover_text(path.display().to_string())
+                            .clicked() {
+                            clicked = Some(path.clone());
                         }
-                        Err(_) => {
-                            // Handle the error case here
-                            ui.label("Failed to read directory");
+                    }
+
+                    // The selection can only change once the list is drawn
+                    if let Some(path) = clicked {
+                        if self.file_paths.contains(&path) {
+                            self.file_paths.retain(|p| p != &path);
+                        } else {
+                            self.file_paths.push(path);
                         }
                     }
                 });
@@ -156,7 +633,7 @@ impl eframe::App for MyApp {
             egui::TopBottomPanel::bottom("bottom_panel").show(ctx, |ui| {
                 if !self.file_paths.is_empty() {
                     let paths_arc: Arc<[PathBuf]> = Arc::from(self.file_paths.clone().into_iter().collect::<Box<[_]>>());
-                    self.plot_manager.cutter.cut_handler_ui(ui, paths_arc);
+                    self.plot_manager.cutter.cut_handler_ui(ui, paths_arc, &self.settings);
                 }
                 
             });
@@ -166,5 +643,49 @@ impl eframe::App for MyApp {
             });
 
         }
+
+        self.confirm_load_window(ctx);
+
+        // "Apply Cuts and Rebuild" was pressed in the cut panel. A press during a load waits for it to finish.
+        if self.plot_manager.cutter.rebuild_requested && self.background_load.is_none() {
+            self.plot_manager.cutter.rebuild_requested = false;
+            if !self.file_paths.is_empty() {
+                self.request_load(true, Vec::new());
+            }
+        }
+
+        // "Build Per File" was pressed in the plot toolbar. A press during a load waits for it to finish.
+        if self.background_load.is_none() {
+            if let Some(names) = self.plot_manager.per_file_request.take() {
+                if !self.file_paths.is_empty() {
+                    self.request_load(self.cuts_applied, names);
+                }
+            }
+        }
+
+        // "Refill With Cut" was pressed in the plot toolbar. A press during a load waits for it to finish.
+        if self.histograms_loaded && self.background_load.is_none() {
+            if let Some(names) = self.plot_manager.cut_refill_request.take() {
+                self.refill_with_cut(names);
+            }
+        }
+
+        // A time calibration was changed, refill its histograms from the columns kept at load once it stops changing
+        if self.settings.time_calibrations != self.seen_calibrations {
+            self.seen_calibrations = self.settings.time_calibrations.clone();
+            self.calibrations_changed = Some(Instant::now());
+        }
+        if let Some(changed) = self.calibrations_changed.filter(|_| self.settings.live_recalibration) {
+            if changed.elapsed() < RECALIBRATION_DELAY {
+                ctx.request_repaint_after(RECALIBRATION_DELAY - changed.elapsed());
+            } else {
+                self.calibrations_changed = None;
+                match recalibrate(&mut self.plot_manager.histogrammer, &self.settings) {
+                    Ok(refilled) if !refilled.is_empty() => ctx.request_repaint(),
+                    Ok(_) => {}
+                    Err(e) => eprintln!("Failed to recalibrate the time histograms: {}", e),
+                }
+            }
+        }
     }
 }
\ No newline at end of file